@@ -0,0 +1,84 @@
+use crate::player::PlayerState;
+use crate::storage::Storage;
+use crate::world::World;
+use std::fs;
+use std::time::SystemTime;
+
+// Autosaves cycle through a few files so a bad save never overwrites the
+// only good one. Slots are numbered from 0 and live next to data.cade. A
+// database is saved in place instead, see Storage::autosave.
+pub const SLOTS: usize = 3;
+// Game ticks (seconds) between autosaves
+pub const INTERVAL: u32 = 60;
+// Where the S key saves to
+pub const MANUAL_SAVE: &str = "data.cade";
+
+pub fn slot_file(slot: usize) -> String {
+    format!("autosave_{}.cade", slot)
+}
+
+fn modified(file_name: &str) -> Option<SystemTime> {
+    fs::metadata(file_name)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+pub struct Autosave {
+    pub slots: usize,
+    pub interval: u32,
+    ticks: u32,
+    next_slot: usize,
+}
+
+impl Autosave {
+    // Carries on from the oldest slot so restarting the game doesn't
+    // overwrite the most recent autosave
+    pub fn new(slots: usize, interval: u32) -> Self {
+        let next_slot = (0..slots)
+            .min_by_key(|&slot| modified(&slot_file(slot)))
+            .unwrap_or(0);
+        Autosave {
+            slots,
+            interval,
+            ticks: 0,
+            next_slot,
+        }
+    }
+
+    // Called once per game tick, saves through whichever storage the game
+    // was started with
+    pub fn tick(&mut self, storage: &mut dyn Storage, world: &mut World, player: PlayerState) {
+        self.ticks += 1;
+        if self.slots == 0 || self.ticks < self.interval {
+            return;
+        }
+        self.ticks = 0;
+
+        if let Err(e) = storage.autosave(self.next_slot, world, Some(player)) {
+            println!("Autosave failed: {}", e);
+        }
+        self.next_slot = (self.next_slot + 1) % self.slots;
+    }
+}
+
+// The most recently written save that still loads, trying the manual save
+// and every autosave slot
+pub fn load_newest(slots: usize) -> Option<(World, Option<PlayerState>)> {
+    let mut candidates: Vec<(SystemTime, String)> = (0..slots)
+        .map(slot_file)
+        .chain(std::iter::once(MANUAL_SAVE.to_string()))
+        .filter_map(|file_name| Some((modified(&file_name)?, file_name)))
+        .collect();
+    candidates.sort();
+
+    candidates
+        .into_iter()
+        .rev()
+        .find_map(|(_, file_name)| match World::from_file(&file_name) {
+            Ok(saved) => Some(saved),
+            Err(e) => {
+                println!("Skipping {}: {}", file_name, e);
+                None
+            }
+        })
+}
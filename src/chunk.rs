@@ -0,0 +1,80 @@
+use crate::resource::ResourceTile;
+use crate::world::Blocks;
+
+// Number of tiles along each side of a chunk
+pub const CHUNK_SIZE: usize = 32;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ChunkPos {
+    pub x: i32,
+    pub y: i32,
+}
+
+impl ChunkPos {
+    pub fn new(x: i32, y: i32) -> Self {
+        ChunkPos { x, y }
+    }
+
+    // Chunk that contains the given tile, works for negative tiles too
+    pub fn from_tile(tile_x: i32, tile_y: i32) -> Self {
+        ChunkPos {
+            x: tile_x.div_euclid(CHUNK_SIZE as i32),
+            y: tile_y.div_euclid(CHUNK_SIZE as i32),
+        }
+    }
+
+    // Tile coordinates of the top left corner of the chunk
+    pub fn origin(&self) -> (i32, i32) {
+        (self.x * CHUNK_SIZE as i32, self.y * CHUNK_SIZE as i32)
+    }
+}
+
+// Position of a tile inside its chunk
+pub fn local_pos(tile_x: i32, tile_y: i32) -> (usize, usize) {
+    (
+        tile_x.rem_euclid(CHUNK_SIZE as i32) as usize,
+        tile_y.rem_euclid(CHUNK_SIZE as i32) as usize,
+    )
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Chunk {
+    tiles: [[Blocks; CHUNK_SIZE]; CHUNK_SIZE],
+    resources: [[Option<ResourceTile>; CHUNK_SIZE]; CHUNK_SIZE],
+    // Tiles the player has seen at some point, see vision.rs
+    explored: [[bool; CHUNK_SIZE]; CHUNK_SIZE],
+}
+
+impl Chunk {
+    pub fn new(fill: Blocks) -> Self {
+        Chunk {
+            tiles: [[fill; CHUNK_SIZE]; CHUNK_SIZE],
+            resources: [[None; CHUNK_SIZE]; CHUNK_SIZE],
+            explored: [[false; CHUNK_SIZE]; CHUNK_SIZE],
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Blocks {
+        self.tiles[x][y]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, block: Blocks) {
+        self.tiles[x][y] = block;
+    }
+
+    pub fn get_resource(&self, x: usize, y: usize) -> Option<ResourceTile> {
+        self.resources[x][y]
+    }
+
+    pub fn set_resource(&mut self, x: usize, y: usize, resource: Option<ResourceTile>) {
+        self.resources[x][y] = resource;
+    }
+
+    pub fn is_explored(&self, x: usize, y: usize) -> bool {
+        self.explored[x][y]
+    }
+
+    pub fn set_explored(&mut self, x: usize, y: usize, explored: bool) {
+        self.explored[x][y] = explored;
+    }
+}
@@ -0,0 +1,72 @@
+use crate::world::World;
+use raylib::prelude::*;
+
+// Whether a tile stops the player. Only solid tiles do for now, building
+// footprints can be added here without touching the movement code.
+pub fn blocks(world: &World, x: i32, y: i32) -> bool {
+    world.is_solid(x, y)
+}
+
+// Moves a circle by delta, one axis at a time, stopping it where it touches a
+// blocking tile. The part of the move that runs along a wall is kept, so
+// walking into a wall at an angle slides along it. Long moves are split into
+// steps no longer than the radius so the circle can't skip over a corner.
+pub fn slide(pos: Vector2, delta: Vector2, radius: f32, world: &World) -> Vector2 {
+    let steps = (delta.x.abs().max(delta.y.abs()) / radius).ceil().max(1.0);
+    let step = delta / steps;
+    let mut pos = pos;
+    for _ in 0..steps as usize {
+        if step.x != 0.0 {
+            pos.x += step.x;
+            pos = push_out(pos, radius, true, step.x, world);
+        }
+        if step.y != 0.0 {
+            pos.y += step.y;
+            pos = push_out(pos, radius, false, step.y, world);
+        }
+    }
+    pos
+}
+
+// Pushes the circle back out of every blocking tile it overlaps, along x if
+// along_x is set and y otherwise, against the direction it was moving in
+fn push_out(pos: Vector2, radius: f32, along_x: bool, moving: f32, world: &World) -> Vector2 {
+    let tile_size = world.tile_size as f32;
+    let (min_x, min_y) = world.tile_at(pos - Vector2::new(radius, radius));
+    let (max_x, max_y) = world.tile_at(pos + Vector2::new(radius, radius));
+
+    let mut pos = pos;
+    for x in min_x..=max_x {
+        for y in min_y..=max_y {
+            if !blocks(world, x, y) {
+                continue;
+            }
+            let (left, top) = (x as f32 * tile_size, y as f32 * tile_size);
+            let (right, bottom) = (left + tile_size, top + tile_size);
+            // Offset from the nearest point of the tile to the centre
+            let off_x = pos.x - pos.x.clamp(left, right);
+            let off_y = pos.y - pos.y.clamp(top, bottom);
+            if off_x * off_x + off_y * off_y >= radius * radius {
+                continue;
+            }
+            // How far the centre has to stay from the side being hit. Past a
+            // corner that's less than the radius, which rounds the corner off.
+            if along_x {
+                let gap = (radius * radius - off_y * off_y).sqrt();
+                pos.x = if moving > 0.0 {
+                    left - gap
+                } else {
+                    right + gap
+                };
+            } else {
+                let gap = (radius * radius - off_x * off_x).sqrt();
+                pos.y = if moving > 0.0 {
+                    top - gap
+                } else {
+                    bottom + gap
+                };
+            }
+        }
+    }
+    pos
+}
@@ -0,0 +1,100 @@
+use crate::light;
+use std::f32::consts::TAU;
+
+// Ticks in a whole day and night, a tick is a second of game time
+pub const DAY_LENGTH: u64 = 600;
+// How high the sun has to be for full daylight, dawn and dusk fade in and
+// out while it's within this far of the horizon
+const TWILIGHT: f32 = 0.2;
+
+// The sun rises at tick 0 and then every day_length ticks
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DayCycle {
+    pub day_length: u64,
+    // Ambient light in the middle of the night, 0..1
+    pub night_ambient: f32,
+}
+
+impl DayCycle {
+    pub fn new(day_length: u64) -> Self {
+        DayCycle {
+            day_length: day_length.max(1),
+            night_ambient: light::NIGHT_AMBIENT,
+        }
+    }
+
+    // How far through the day it is, 0 at sunrise, 0.25 at noon, 0.5 at
+    // sunset and 0.75 at midnight
+    pub fn time_of_day(&self, ticks: u64) -> f32 {
+        (ticks % self.day_length) as f32 / self.day_length as f32
+    }
+
+    // 1 while the sun is up, 0 at night and in between at dawn and dusk
+    pub fn daylight(&self, ticks: u64) -> f32 {
+        let sun = (self.time_of_day(ticks) * TAU).sin();
+        ((sun + TWILIGHT) / (2.0 * TWILIGHT)).clamp(0.0, 1.0)
+    }
+
+    // Light that reaches every tile, see light.rs
+    pub fn ambient(&self, ticks: u64) -> f32 {
+        self.night_ambient + (1.0 - self.night_ambient) * self.daylight(ticks)
+    }
+}
+
+impl Default for DayCycle {
+    fn default() -> Self {
+        Self::new(DAY_LENGTH)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f32 = 1e-4;
+
+    // The tick a fraction of the way through the day
+    fn at(day: &DayCycle, time_of_day: f32) -> u64 {
+        (time_of_day * day.day_length as f32).round() as u64
+    }
+
+    #[test]
+    fn light_follows_the_sun() {
+        let day = DayCycle::new(1000);
+        // Sunrise and sunset are halfway through the twilight fade
+        assert!((day.daylight(at(&day, 0.0)) - 0.5).abs() < EPSILON);
+        assert!((day.daylight(at(&day, 0.5)) - 0.5).abs() < EPSILON);
+        assert_eq!(day.daylight(at(&day, 0.25)), 1.0);
+        assert_eq!(day.daylight(at(&day, 0.75)), 0.0);
+
+        assert_eq!(day.ambient(at(&day, 0.25)), 1.0);
+        assert!((day.ambient(at(&day, 0.75)) - day.night_ambient).abs() < EPSILON);
+    }
+
+    #[test]
+    fn twilight_is_clamped() {
+        let day = DayCycle::new(DAY_LENGTH);
+        for ticks in 0..day.day_length {
+            let daylight = day.daylight(ticks);
+            assert!((0.0..=1.0).contains(&daylight));
+            let sun = (day.time_of_day(ticks) * TAU).sin();
+            if sun >= TWILIGHT {
+                assert_eq!(daylight, 1.0);
+            } else if sun <= -TWILIGHT {
+                assert_eq!(daylight, 0.0);
+            } else {
+                assert!(daylight > 0.0 && daylight < 1.0);
+            }
+        }
+    }
+
+    #[test]
+    fn days_repeat() {
+        let day = DayCycle::new(600);
+        for ticks in [0, 1, 150, 299, 450, 599] {
+            assert_eq!(day.daylight(ticks), day.daylight(ticks + 3 * 600));
+        }
+        // A zero length day would divide by zero
+        assert_eq!(DayCycle::new(0).day_length, 1);
+    }
+}
@@ -0,0 +1,270 @@
+use crate::world;
+use raylib::prelude::*;
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct EntityId(pub u32);
+
+// Which way a building faces, buildings are drawn facing North
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Direction {
+    North = 0,
+    East = 1,
+    South = 2,
+    West = 3,
+}
+
+impl Direction {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Direction::North),
+            1 => Some(Direction::East),
+            2 => Some(Direction::South),
+            3 => Some(Direction::West),
+            _ => None,
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        self as u8
+    }
+
+    pub fn rotate_clockwise(self) -> Self {
+        Self::from_u8((self.to_u8() + 1) % 4).unwrap()
+    }
+
+    pub fn degrees(self) -> f32 {
+        self.to_u8() as f32 * 90.0
+    }
+}
+
+// Save files store these discriminants
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum EntityKind {
+    Chest = 1,
+    Drill = 2,
+    Assembler = 3,
+    // Lights the tiles around it, see light.rs
+    Lamp = 4,
+}
+
+impl EntityKind {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(EntityKind::Chest),
+            2 => Some(EntityKind::Drill),
+            3 => Some(EntityKind::Assembler),
+            4 => Some(EntityKind::Lamp),
+            _ => None,
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        self as u8
+    }
+
+    // Width and height in tiles, every building is square
+    pub fn size(self) -> i32 {
+        match self {
+            EntityKind::Chest | EntityKind::Lamp => 1,
+            EntityKind::Drill => 2,
+            EntityKind::Assembler => 3,
+        }
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            EntityKind::Chest => EntityKind::Drill,
+            EntityKind::Drill => EntityKind::Assembler,
+            EntityKind::Assembler => EntityKind::Lamp,
+            EntityKind::Lamp => EntityKind::Chest,
+        }
+    }
+
+    // Sprites are 32 pixels per tile of footprint, along the sixth row of the atlas
+    pub fn texture_section(self) -> Rectangle {
+        let x = match self {
+            EntityKind::Chest => 0.0,
+            EntityKind::Drill => 32.0,
+            EntityKind::Assembler => 96.0,
+            EntityKind::Lamp => 192.0,
+        };
+        Rectangle {
+            x,
+            y: 160.0,
+            width: 32.0 * self.size() as f32,
+            height: 32.0 * self.size() as f32,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Entity {
+    pub id: EntityId,
+    pub kind: EntityKind,
+    // Top left tile of the footprint
+    pub x: i32,
+    pub y: i32,
+    pub direction: Direction,
+}
+
+impl Entity {
+    // Every tile covered by the building
+    pub fn footprint(&self) -> impl Iterator<Item = (i32, i32)> {
+        let (x, y, size) = (self.x, self.y, self.kind.size());
+        (0..size).flat_map(move |i| (0..size).map(move |j| (x + i, y + j)))
+    }
+}
+
+// The building P puts down next and which way it faces, cycled with B and
+// turned with Q by both the player and the editor's selector
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Blueprint {
+    pub kind: EntityKind,
+    pub direction: Direction,
+}
+
+impl Default for Blueprint {
+    fn default() -> Self {
+        Blueprint {
+            kind: EntityKind::Chest,
+            direction: Direction::North,
+        }
+    }
+}
+
+impl Blueprint {
+    pub fn next_kind(&mut self) {
+        self.kind = self.kind.next();
+        println!("Building: {:?}", self.kind);
+    }
+
+    pub fn rotate(&mut self) {
+        self.direction = self.direction.rotate_clockwise();
+        println!("Direction: {:?}", self.direction);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PlacementError {
+    // Another building already covers this tile
+    Occupied { x: i32, y: i32, by: EntityId },
+    // The terrain under this tile can't be built on
+    Blocked { x: i32, y: i32 },
+}
+
+impl fmt::Display for PlacementError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlacementError::Occupied { x, y, by } => {
+                write!(f, "tile ({}, {}) is occupied by entity {}", x, y, by.0)
+            }
+            PlacementError::Blocked { x, y } => write!(f, "can't build on tile ({}, {})", x, y),
+        }
+    }
+}
+
+// All the buildings in the world plus a grid of which tiles they cover
+pub struct Entities {
+    next_id: u32,
+    entities: HashMap<EntityId, Entity>,
+    occupancy: HashMap<(i32, i32), EntityId>,
+    // Revision of the last time anything was added, removed or handed out
+    // mutably, see world::next_revision
+    revision: u64,
+}
+
+impl Default for Entities {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Entities {
+    pub fn new() -> Self {
+        Entities {
+            next_id: 1,
+            entities: HashMap::new(),
+            occupancy: HashMap::new(),
+            revision: 0,
+        }
+    }
+
+    pub fn place(
+        &mut self,
+        kind: EntityKind,
+        x: i32,
+        y: i32,
+        direction: Direction,
+    ) -> Result<EntityId, PlacementError> {
+        let id = EntityId(self.next_id);
+        self.insert(Entity {
+            id,
+            kind,
+            x,
+            y,
+            direction,
+        })?;
+        Ok(id)
+    }
+
+    // Adds an entity that already has an id, used when loading saves
+    pub fn insert(&mut self, entity: Entity) -> Result<(), PlacementError> {
+        if let Some((x, y, by)) = entity
+            .footprint()
+            .find_map(|(x, y)| self.occupancy.get(&(x, y)).map(|&by| (x, y, by)))
+        {
+            return Err(PlacementError::Occupied { x, y, by });
+        }
+
+        for tile in entity.footprint() {
+            self.occupancy.insert(tile, entity.id);
+        }
+        self.next_id = self.next_id.max(entity.id.0 + 1);
+        self.entities.insert(entity.id, entity);
+        self.revision = world::next_revision();
+        Ok(())
+    }
+
+    pub fn remove(&mut self, id: EntityId) -> Option<Entity> {
+        let entity = self.entities.remove(&id)?;
+        for tile in entity.footprint() {
+            self.occupancy.remove(&tile);
+        }
+        self.revision = world::next_revision();
+        Some(entity)
+    }
+
+    pub fn get(&self, id: EntityId) -> Option<&Entity> {
+        self.entities.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: EntityId) -> Option<&mut Entity> {
+        self.revision = world::next_revision();
+        self.entities.get_mut(&id)
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    // The building covering a tile, if any
+    pub fn at(&self, x: i32, y: i32) -> Option<&Entity> {
+        self.occupancy
+            .get(&(x, y))
+            .and_then(|id| self.entities.get(id))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Entity> {
+        self.entities.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+}
@@ -0,0 +1,529 @@
+use crate::light::LightMap;
+use crate::my_ray::{self, ViewRay};
+use crate::world::World;
+use raylib::prelude::*;
+
+// Floor and sky for the FPS view. Raylib can't texture the floor in
+// perspective for us, so it's cast on the CPU into a small image that's
+// stretched over the screen before the walls are drawn on top.
+
+// Screen pixels per floor pixel along each axis
+const FLOOR_SCALE: i32 = 4;
+// Brightness of anything at the view distance
+const MIN_BRIGHTNESS: f32 = 0.2;
+
+// How lit something is at a distance, fading towards the edge of the view
+pub fn fog(distance: f32, max_distance: f32) -> f32 {
+    1.0 - (1.0 - MIN_BRIGHTNESS) * (distance / max_distance).min(1.0)
+}
+
+// Darkens a colour, brightness runs 0..1
+fn scale(color: Color, brightness: f32) -> Color {
+    Color::new(
+        (color.r as f32 * brightness) as u8,
+        (color.g as f32 * brightness) as u8,
+        (color.b as f32 * brightness) as u8,
+        color.a,
+    )
+}
+
+// Where the view is from and how it maps onto the screen. The view spans
+// `fov` degrees over `view_width` screen pixels, projected the same way as
+// the rays in my_ray::RayBatch::cast.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FpsView {
+    pub pos: Vector2,
+    pub angle: f32,
+    pub fov: f32,
+    pub view_width: f32,
+    pub screen_height: f32,
+    pub max_distance: f32,
+}
+
+impl FpsView {
+    // Angle of a screen column relative to the centre of the view
+    fn column_offset(&self, screen_x: f32) -> f32 {
+        my_ray::view_angle(screen_x / self.view_width, self.fov)
+    }
+
+    // Screen x of an angle from the centre of the view, which has to be
+    // less than 90 degrees either way
+    fn offset_column(&self, offset: f32) -> f32 {
+        my_ray::view_across(offset, self.fov) * self.view_width
+    }
+
+    // Distance from the eye to the screen, in screen pixels. Both axes use
+    // it so walls and sprites keep their proportions at any fov.
+    pub fn focal_length(&self) -> f32 {
+        self.view_width / 2.0 / (self.fov / 2.0).to_radians().tan()
+    }
+
+    // Screen pixels per world unit, across or up, for something at a given depth
+    pub fn scale_at(&self, depth: f32) -> f32 {
+        self.focal_length() / depth
+    }
+
+    // How far in front of the camera a point is and how far to its right
+    fn camera_space(&self, point: Vector2) -> (f32, f32) {
+        let (sin, cos) = self.angle.to_radians().sin_cos();
+        let relative = point - self.pos;
+        (
+            relative.x * cos + relative.y * sin,
+            relative.y * cos - relative.x * sin,
+        )
+    }
+
+    // Where a point in camera space `height` world units above the floor
+    // ends up on screen, the same way walls are scaled
+    fn project(&self, depth: f32, side: f32, height: f32, tile_size: f32) -> Vector2 {
+        let scale = self.scale_at(depth);
+        Vector2::new(
+            self.view_width / 2.0 + side * scale,
+            self.screen_height / 2.0 + (tile_size / 2.0 - height) * scale,
+        )
+    }
+}
+
+// Lines are cut off this close in front of the camera, in world units
+const NEAR: f32 = 1.0;
+
+// Draws a line between two points given as (floor position, height above the
+// floor) in world units. It isn't hidden by walls, only clipped to the part
+// in front of the camera.
+pub fn draw_line_3d(
+    d: &mut RaylibDrawHandle,
+    view: &FpsView,
+    from: (Vector2, f32),
+    to: (Vector2, f32),
+    tile_size: f32,
+    color: Color,
+) {
+    let (mut depth_a, mut side_a) = view.camera_space(from.0);
+    let (mut depth_b, mut side_b) = view.camera_space(to.0);
+    let (mut height_a, mut height_b) = (from.1, to.1);
+    if depth_a < NEAR && depth_b < NEAR {
+        return;
+    }
+
+    // Moves whichever end is behind the near plane onto it
+    let t = (NEAR - depth_a) / (depth_b - depth_a);
+    if depth_a < NEAR {
+        side_a += (side_b - side_a) * t;
+        height_a += (height_b - height_a) * t;
+        depth_a = NEAR;
+    } else if depth_b < NEAR {
+        side_b = side_a + (side_b - side_a) * t;
+        height_b = height_a + (height_b - height_a) * t;
+        depth_b = NEAR;
+    }
+
+    d.draw_line_ex(
+        view.project(depth_a, side_a, height_a, tile_size),
+        view.project(depth_b, side_b, height_b, tile_size),
+        2.0,
+        color,
+    );
+}
+
+// Outlines the box standing on the floor between two corners, `height` world
+// units tall. Flat boxes outline an area of floor and thin ones a wall face.
+pub fn draw_box(
+    d: &mut RaylibDrawHandle,
+    view: &FpsView,
+    min: Vector2,
+    max: Vector2,
+    height: f32,
+    tile_size: f32,
+    color: Color,
+) {
+    let corners = [
+        min,
+        Vector2::new(max.x, min.y),
+        max,
+        Vector2::new(min.x, max.y),
+    ];
+    for i in 0..corners.len() {
+        let (a, b) = (corners[i], corners[(i + 1) % corners.len()]);
+        draw_line_3d(d, view, (a, 0.0), (b, 0.0), tile_size, color);
+        if height > 0.0 {
+            draw_line_3d(d, view, (a, height), (b, height), tile_size, color);
+            draw_line_3d(d, view, (a, 0.0), (a, height), tile_size, color);
+        }
+    }
+}
+
+// What's drawn above the horizon
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Sky {
+    Color(Color),
+    // A section of the atlas wrapped once around the horizon
+    Atlas(Rectangle),
+}
+
+impl Sky {
+    // "r,g,b" for a plain colour or "atlas x,y,width,height" for a section of
+    // the atlas, the way --sky takes it
+    pub fn parse(text: &str) -> Option<Self> {
+        match text.trim().strip_prefix("atlas") {
+            Some(section) => match parse_list::<u32>(section)?[..] {
+                [x, y, width, height] if width > 0 && height > 0 => Some(Sky::Atlas(Rectangle {
+                    x: x as f32,
+                    y: y as f32,
+                    width: width as f32,
+                    height: height as f32,
+                })),
+                _ => None,
+            },
+            None => match parse_list::<u8>(text)?[..] {
+                [r, g, b] => Some(Sky::Color(Color::new(r, g, b, 255))),
+                _ => None,
+            },
+        }
+    }
+}
+
+// Comma separated numbers, None if any of them doesn't parse
+fn parse_list<T: std::str::FromStr>(text: &str) -> Option<Vec<T>> {
+    text.split(',')
+        .map(|number| number.trim().parse().ok())
+        .collect()
+}
+
+// CPU copy of the texture atlas so single pixels can be sampled
+pub struct AtlasPixels {
+    width: usize,
+    height: usize,
+    pixels: Vec<Color>,
+}
+
+impl AtlasPixels {
+    pub fn from_image(image: &Image) -> Self {
+        AtlasPixels {
+            width: image.width() as usize,
+            height: image.height() as usize,
+            pixels: image.get_image_data().to_vec(),
+        }
+    }
+
+    // u and v run 0..1 across the section
+    pub fn sample(&self, section: Rectangle, u: f32, v: f32) -> Color {
+        let x = section.x as usize + ((u * section.width) as usize).min(section.width as usize - 1);
+        let y =
+            section.y as usize + ((v * section.height) as usize).min(section.height as usize - 1);
+        if x < self.width && y < self.height {
+            self.pixels[y * self.width + x]
+        } else {
+            Color::BLACK
+        }
+    }
+}
+
+pub struct FloorCaster {
+    pub sky: Sky,
+    atlas: AtlasPixels,
+    width: usize,
+    height: usize,
+    // RGBA, uploaded to the texture every frame
+    pixels: Vec<u8>,
+    texture: Texture2D,
+}
+
+impl FloorCaster {
+    pub fn new(
+        rl: &mut RaylibHandle,
+        thread: &RaylibThread,
+        atlas_file: &str,
+        screen_width: i32,
+        screen_height: i32,
+    ) -> Result<Self, String> {
+        let atlas = AtlasPixels::from_image(&Image::load_image(atlas_file)?);
+        let (width, height) = (screen_width / FLOOR_SCALE, screen_height / FLOOR_SCALE);
+        let texture = rl.load_texture_from_image(
+            thread,
+            &Image::gen_image_color(width, height, Color::BLACK),
+        )?;
+
+        Ok(FloorCaster {
+            sky: Sky::Color(Color::SKYBLUE),
+            atlas,
+            width: width as usize,
+            height: height as usize,
+            pixels: vec![0; width as usize * height as usize * 4],
+            texture,
+        })
+    }
+
+    // Recasts the floor and sky for a view, the sky only gets the ambient light
+    pub fn update(&mut self, view: &FpsView, light: &LightMap, world: &World) {
+        let tile_size = world.tile_size as f32;
+        let screen_height = (self.height as i32 * FLOOR_SCALE) as f32;
+        let horizon = screen_height / 2.0;
+
+        for column in 0..self.width {
+            let screen_x = (column as f32 + 0.5) * FLOOR_SCALE as f32;
+            let offset = view.column_offset(screen_x);
+            let ray_angle = view.angle + offset;
+            let direction =
+                Vector2::new(ray_angle.to_radians().cos(), ray_angle.to_radians().sin());
+            // Undoes the fish eye correction applied to the walls
+            let stretch = 1.0 / offset.to_radians().cos();
+
+            for row in 0..self.height {
+                let screen_y = (row as f32 + 0.5) * FLOOR_SCALE as f32;
+                let color = if screen_y < horizon {
+                    scale(self.sky_pixel(ray_angle, screen_y / horizon), light.ambient)
+                } else {
+                    // Walls at depth d reach down to horizon + focal_length * tile_size / 2d
+                    let depth = view.focal_length() * tile_size / (2.0 * (screen_y - horizon));
+                    let distance = depth * stretch;
+                    self.floor_pixel(
+                        view.pos + direction * distance,
+                        distance,
+                        view.max_distance,
+                        light,
+                        world,
+                    )
+                };
+
+                let index = (row * self.width + column) * 4;
+                self.pixels[index..index + 4].copy_from_slice(&[color.r, color.g, color.b, 255]);
+            }
+        }
+
+        self.texture.update_texture(&self.pixels);
+    }
+
+    fn sky_pixel(&self, angle: f32, v: f32) -> Color {
+        match self.sky {
+            Sky::Color(color) => color,
+            Sky::Atlas(section) => self
+                .atlas
+                .sample(section, angle.rem_euclid(360.0) / 360.0, v),
+        }
+    }
+
+    // The tile under a point, with its ore drawn over it
+    fn floor_pixel(
+        &self,
+        point: Vector2,
+        distance: f32,
+        max_distance: f32,
+        light: &LightMap,
+        world: &World,
+    ) -> Color {
+        if distance > max_distance {
+            return Color::BLACK;
+        }
+
+        let (tile_x, tile_y) = world.tile_at(point);
+        let Some(block) = world.get_tile(tile_x, tile_y) else {
+            return Color::BLACK;
+        };
+
+        let tile_size = world.tile_size as f32;
+        let u = point.x / tile_size - tile_x as f32;
+        let v = point.y / tile_size - tile_y as f32;
+        let mut color = self.atlas.sample(world.tiles.get(block).atlas.into(), u, v);
+        if let Some(resource) = world.get_resource(tile_x, tile_y) {
+            let ore = self.atlas.sample(resource.kind.texture_section(), u, v);
+            if ore.a > 0 {
+                color = ore;
+            }
+        }
+
+        let brightness = fog(distance, max_distance) * light.level(tile_x, tile_y);
+        scale(color, brightness)
+    }
+
+    // Stretches the floor and sky over the whole screen
+    pub fn draw(&self, d: &mut RaylibDrawHandle, screen_width: i32, screen_height: i32) {
+        d.draw_texture_pro(
+            &self.texture,
+            Rectangle {
+                x: 0.0,
+                y: 0.0,
+                width: self.width as f32,
+                height: self.height as f32,
+            },
+            Rectangle {
+                x: 0.0,
+                y: 0.0,
+                width: screen_width as f32,
+                height: screen_height as f32,
+            },
+            Vector2::new(0.0, 0.0),
+            0.0,
+            Color::WHITE,
+        );
+    }
+}
+
+// A flat picture that always faces the camera, for things that aren't walls
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Sprite {
+    // Centre of the sprite's footprint, in world space
+    pub pos: Vector2,
+    // Width and height, in world space
+    pub size: f32,
+    pub section: Rectangle,
+    // How lit it is before fog, 0..1
+    pub brightness: f32,
+}
+
+// One sprite per building, standing in the middle of the tiles it covers
+pub fn entity_sprites(world: &World, light: &LightMap) -> Vec<Sprite> {
+    let tile_size = world.tile_size as f32;
+    world
+        .entities
+        .iter()
+        .map(|entity| {
+            let size = entity.kind.size() as f32;
+            Sprite {
+                pos: Vector2::new(
+                    (entity.x as f32 + size / 2.0) * tile_size,
+                    (entity.y as f32 + size / 2.0) * tile_size,
+                ),
+                size: size * tile_size,
+                section: entity.kind.texture_section(),
+                brightness: middle_light(entity.x, entity.y, entity.kind.size(), light),
+            }
+        })
+        .collect()
+}
+
+// How lit the middle of a footprint is: its middle tile, or the average of
+// the four tiles meeting there when it's an even number of tiles across
+fn middle_light(x: i32, y: i32, size: i32, light: &LightMap) -> f32 {
+    let (mid_x, mid_y) = (x + size / 2, y + size / 2);
+    if size % 2 == 1 {
+        return light.level(mid_x, mid_y);
+    }
+    let tiles = [(0, 0), (-1, 0), (0, -1), (-1, -1)];
+    tiles
+        .iter()
+        .map(|&(dx, dy)| light.level(mid_x + dx, mid_y + dy))
+        .sum::<f32>()
+        / tiles.len() as f32
+}
+
+// Draws sprites over the walls, furthest first. Each ray column only shows
+// the part of a sprite that's nearer than the wall that ray hit.
+pub fn draw_sprites(
+    d: &mut RaylibDrawHandle,
+    texture_atlas: &Texture2D,
+    view: &FpsView,
+    rays: &[ViewRay],
+    sprites: &[Sprite],
+    tile_size: f32,
+) {
+    if rays.is_empty() {
+        return;
+    }
+    let column_width = view.view_width / rays.len() as f32;
+    let horizon = view.screen_height / 2.0;
+
+    // (depth, distance, left, right, sprite)
+    let mut visible: Vec<(f32, f32, f32, f32, &Sprite)> = sprites
+        .iter()
+        .filter_map(|sprite| {
+            let to_sprite = sprite.pos - view.pos;
+            let distance = (to_sprite.x * to_sprite.x + to_sprite.y * to_sprite.y).sqrt();
+            let offset = (to_sprite.y.atan2(to_sprite.x).to_degrees() - view.angle + 180.0)
+                .rem_euclid(360.0)
+                - 180.0;
+            // Same correction as the walls so sprites sit flat on the floor
+            let depth = distance * offset.to_radians().cos();
+            if depth < 1.0 || distance > view.max_distance {
+                return None;
+            }
+            // It may poke in from outside the view
+            let centre = view.offset_column(offset);
+            let half_width = view.scale_at(depth) * sprite.size / 2.0;
+            let (left, right) = (centre - half_width, centre + half_width);
+            if right <= 0.0 || left >= view.view_width {
+                return None;
+            }
+            Some((depth, distance, left, right, sprite))
+        })
+        .collect();
+    visible.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    for (depth, distance, left, right, sprite) in visible {
+        let height = view.scale_at(depth) * sprite.size;
+        // Standing on the floor, where a wall at the same depth would end
+        let bottom = horizon + view.scale_at(depth) * tile_size / 2.0;
+        let tint = scale(
+            Color::WHITE,
+            fog(distance, view.max_distance) * sprite.brightness,
+        );
+
+        let first = ((left / column_width).floor().max(0.0)) as usize;
+        let last = ((right / column_width).ceil() as usize).min(rays.len());
+        for (i, ray) in rays.iter().enumerate().take(last).skip(first) {
+            if ray.hit.is_some() && ray.distance <= depth {
+                continue;
+            }
+
+            // The part of this ray column the sprite covers
+            let x0 = (i as f32 * column_width).max(left);
+            let x1 = ((i + 1) as f32 * column_width).min(right);
+            if x1 <= x0 {
+                continue;
+            }
+            let u0 = (x0 - left) / (right - left);
+            let u1 = (x1 - left) / (right - left);
+
+            d.draw_texture_pro(
+                texture_atlas,
+                Rectangle {
+                    x: sprite.section.x + u0 * sprite.section.width,
+                    y: sprite.section.y,
+                    width: (u1 - u0) * sprite.section.width,
+                    height: sprite.section.height,
+                },
+                Rectangle {
+                    x: x0,
+                    y: bottom - height,
+                    width: x1 - x0,
+                    height,
+                },
+                Vector2::new(0.0, 0.0),
+                0.0,
+                tint,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sky_settings() {
+        assert_eq!(
+            Sky::parse("135,206,235"),
+            Some(Sky::Color(Color::new(135, 206, 235, 255)))
+        );
+        assert_eq!(
+            Sky::parse("atlas 0, 192, 256, 64"),
+            Some(Sky::Atlas(Rectangle {
+                x: 0.0,
+                y: 192.0,
+                width: 256.0,
+                height: 64.0,
+            }))
+        );
+        for bad in [
+            "",
+            "1,2",
+            "1,2,3,4",
+            "256,0,0",
+            "red",
+            "atlas 0,0,0,10",
+            "atlas 1,2,3",
+        ] {
+            assert_eq!(Sky::parse(bad), None, "{:?}", bad);
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use crate::entity::{EntityId, EntityKind};
+use crate::my_ray;
+use crate::world::{self, World};
+use raylib::prelude::*;
+use std::collections::HashMap;
+
+// How dark it gets with the lights off
+pub const NIGHT_AMBIENT: f32 = 0.15;
+// How far a lamp reaches, in tiles
+const LAMP_RADIUS: f32 = 8.0;
+// Rays per lamp, enough that neighbouring rays don't skip tiles at the edge
+// of its light
+const LAMP_RAYS: usize = 360;
+
+// The light from one lamp, kept until a tile it could reach changes
+struct LampLight {
+    x: i32,
+    y: i32,
+    tiles: Vec<((i32, i32), f32)>,
+    // world::next_revision as of the cast
+    cast_at: u64,
+}
+
+// How lit each tile is, from 0 for pitch black to 1 for full daylight. Lamps
+// only light the tiles their rays reach, so walls cast shadows.
+pub struct LightMap {
+    // Light that reaches everywhere, lowered at night
+    pub ambient: f32,
+    lamps: HashMap<EntityId, LampLight>,
+    // Every lamp in the world as of the entities revision in lamps_at
+    known: Vec<(EntityId, i32, i32)>,
+    lamps_at: Option<u64>,
+    // Brightest lamp light on each tile
+    levels: HashMap<(i32, i32), f32>,
+}
+
+impl LightMap {
+    pub fn new() -> Self {
+        LightMap {
+            ambient: 1.0,
+            lamps: HashMap::new(),
+            known: Vec::new(),
+            lamps_at: None,
+            levels: HashMap::new(),
+        }
+    }
+
+    pub fn level(&self, x: i32, y: i32) -> f32 {
+        match self.levels.get(&(x, y)) {
+            Some(&lamp) => lamp.max(self.ambient),
+            None => self.ambient,
+        }
+    }
+
+    // Grey to tint a tile's texture with
+    pub fn tint(&self, x: i32, y: i32) -> Color {
+        let level = (255.0 * self.level(x, y)) as u8;
+        Color::new(level, level, level, 255)
+    }
+
+    // Brings the light up to date for lamps within `reach` world units of
+    // `centre`. A lamp is only recast when it's new or a tile it could reach
+    // changed, lamps further away keep whatever light they had.
+    pub fn update(&mut self, world: &World, centre: Vector2, reach: f32) {
+        let mut changed = false;
+
+        if self.lamps_at != Some(world.entities.revision()) {
+            self.lamps_at = Some(world.entities.revision());
+            self.known = world
+                .entities
+                .iter()
+                .filter(|entity| entity.kind == EntityKind::Lamp)
+                .map(|lamp| (lamp.id, lamp.x, lamp.y))
+                .collect();
+            let known = &self.known;
+            let before = self.lamps.len();
+            self.lamps
+                .retain(|&id, lamp| known.contains(&(id, lamp.x, lamp.y)));
+            changed |= self.lamps.len() != before;
+        }
+
+        let tile_size = world.tile_size as f32;
+        let radius = LAMP_RADIUS * tile_size;
+        let tiles = LAMP_RADIUS.ceil() as i32;
+        for &(id, x, y) in &self.known {
+            let lamp_centre =
+                Vector2::new((x as f32 + 0.5) * tile_size, (y as f32 + 0.5) * tile_size);
+            if my_ray::dist(lamp_centre.x, lamp_centre.y, centre.x, centre.y) > reach + radius {
+                continue;
+            }
+            let stale = match self.lamps.get(&id) {
+                Some(lamp) => world.changed_since(
+                    (x - tiles, y - tiles),
+                    (x + tiles, y + tiles),
+                    lamp.cast_at,
+                ),
+                None => true,
+            };
+            if stale {
+                self.lamps.insert(id, cast_lamp(x, y, world));
+                changed = true;
+            }
+        }
+
+        // Where lamps overlap the brightest one wins
+        if changed {
+            self.levels.clear();
+            for lamp in self.lamps.values() {
+                for &(tile, level) in &lamp.tiles {
+                    let brightest = self.levels.entry(tile).or_insert(0.0);
+                    *brightest = brightest.max(level);
+                }
+            }
+        }
+    }
+}
+
+// Traces the light from a lamp, fading out towards the edge of its reach
+fn cast_lamp(x: i32, y: i32, world: &World) -> LampLight {
+    let cast_at = world::next_revision();
+    let tile_size = world.tile_size as f32;
+    let radius = LAMP_RADIUS * tile_size;
+    let centre = Vector2::new((x as f32 + 0.5) * tile_size, (y as f32 + 0.5) * tile_size);
+
+    let mut levels: HashMap<(i32, i32), f32> = HashMap::new();
+    let mut light = |x: i32, y: i32| {
+        let tile_centre = Vector2::new((x as f32 + 0.5) * tile_size, (y as f32 + 0.5) * tile_size);
+        let distance = my_ray::dist(centre.x, centre.y, tile_centre.x, tile_centre.y);
+        let level = levels.entry((x, y)).or_insert(0.0);
+        *level = level.max(1.0 - distance / radius);
+    };
+    light(x, y);
+    for i in 0..LAMP_RAYS {
+        let angle = i as f32 * 360.0 / LAMP_RAYS as f32;
+        my_ray::trace(centre, angle, tile_size, radius, world, &mut light);
+    }
+
+    LampLight {
+        x,
+        y,
+        tiles: levels.into_iter().collect(),
+        cast_at,
+    }
+}
+
+impl Default for LightMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
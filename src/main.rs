@@ -1,29 +1,53 @@
 // TODO:
-// 1. Do some kind of collision detection
-// 2. Add load world function to the player
-// 3. Something's wrong with the load from file function
-// 4. Minimap Zoom is broken
-// 5. World zoom works
+// 1. Add load world function to the player
+// 2. Minimap Zoom is broken
+// 3. World zoom works
 
 const FPS: u32 = 20;
 
-use player::Player;
+use daynight::DayCycle;
+use fps::{FloorCaster, Sky};
+use mapfile::ColorMap;
+use player::{Controls, Player};
 use render::*;
 use selector::Selector;
 use state::State;
+use storage::{SqliteStorage, Storage};
+use terrain::TerrainSettings;
+use tiles::TileRegistry;
 use world::World;
 
 use raylib::prelude::*;
 use std::io;
 
+pub mod autosave;
+pub mod chunk;
+pub mod collision;
+pub mod daynight;
+pub mod entity;
+pub mod fps;
+pub mod light;
+pub mod mapfile;
 pub mod my_ray;
 pub mod player;
 pub mod render;
+pub mod resource;
+pub mod save;
 pub mod selector;
 pub mod state;
+pub mod storage;
+pub mod terrain;
+pub mod tiles;
+pub mod vision;
 pub mod world;
 
 fn main() -> io::Result<()> {
+    // --bench-rays prints how fast the FPS view's rays can be cast and exits
+    if std::env::args().any(|arg| arg == "--bench-rays") {
+        bench_rays();
+        return Ok(());
+    }
+
     let mut state = State::new();
 
     let (mut rl, thread) = raylib::init()
@@ -35,23 +59,91 @@ fn main() -> io::Result<()> {
         .load_texture(&thread, "./player_sheet.png")
         .expect("Failed to load texture");
 
-    let mut world = World::new(8);
+    // Tile prototypes can be tweaked without recompiling
+    let tiles = TileRegistry::from_file("./tiles.ron").expect("Failed to load tile prototypes");
+
+    // Pass --seed <number> to play on a generated map, --map <file> to start
+    // from a .png or text map, or --load-last to carry on from the newest save.
+    // --db <file> keeps the world in a SQLite database instead of data.cade and
+    // picks up where it left off if the database has been saved to before.
+    let arg = |name: &str| std::env::args().skip_while(|arg| arg != name).nth(1);
+    let seed = arg("--seed").and_then(|seed| seed.parse().ok());
+    let load_last = std::env::args().any(|arg| arg == "--load-last");
+    let mut saved = None;
+    if let Some(file_name) = arg("--db") {
+        let mut db = SqliteStorage::open(&file_name).map_err(invalid_data)?;
+        if !db.is_empty().map_err(invalid_data)? {
+            saved = Some(db.load().map_err(invalid_data)?);
+        }
+        state.storage = Box::new(db);
+    } else if load_last {
+        saved = autosave::load_newest(autosave::SLOTS);
+    }
+    let (mut world, player_state) = match (saved, arg("--map"), seed) {
+        (Some(saved), _, _) => saved,
+        (None, Some(file_name), _) => {
+            let map = if file_name.ends_with(".png") {
+                mapfile::load_png(&file_name, &ColorMap::from_registry(&tiles), &tiles)
+            } else {
+                mapfile::load_ascii(&file_name, &tiles)
+            };
+            (map.map_err(invalid_data)?, None)
+        }
+        (None, None, Some(seed)) => (World::generate(seed, TerrainSettings::default()), None),
+        (None, None, None) => (World::new(8, 8), None),
+    };
+    world.tiles = tiles;
     let mut player = Player::new(&mut world);
+    if let Some(player_state) = player_state {
+        player.restore(player_state, &mut world);
+        state.ticks = player_state.ticks;
+    }
+    // --fov <degrees> and --rays <count> set up the FPS view, which casts one
+    // ray per screen column by default
+    let fov = arg("--fov")
+        .and_then(|fov| fov.parse().ok())
+        .unwrap_or(player.fov);
+    let num_rays = arg("--rays")
+        .and_then(|rays| rays.parse().ok())
+        .unwrap_or(state.screen_width as usize);
+    player.set_resolution(fov.clamp(1.0, 179.0), num_rays, &world);
+    // --controls tank|strafe picks how A and D work, --mouse-look turns with
+    // the mouse and --sensitivity <degrees per pixel> sets how fast
+    if let Some(controls) = arg("--controls").and_then(|name| Controls::from_name(&name)) {
+        player.controls = controls;
+    }
+    player.mouse_look = std::env::args().any(|arg| arg == "--mouse-look");
+    if let Some(sensitivity) = arg("--sensitivity").and_then(|value| value.parse().ok()) {
+        player.set_mouse_sensitivity(sensitivity);
+    }
+    // --day-length <ticks> sets how long a whole day and night lasts
+    if let Some(day_length) = arg("--day-length").and_then(|ticks| ticks.parse().ok()) {
+        state.day = DayCycle::new(day_length);
+    }
     let mut selector = Selector::new();
 
-    /*
-        // Load world from a file
-        let world_result = World::from_file("data.cade");
-        let mut world = match world_result {
-            Ok(world) => world,
-            Err(e) => {
-                eprintln!("Error loading world: {}", e);
-                return Err(e);
-            }
-        };
-    */
-
     let mut renderer = Renderer::new(RendererType::Minimap);
+    renderer.floor = match FloorCaster::new(
+        &mut rl,
+        &thread,
+        "./player_sheet.png",
+        state.screen_width,
+        state.screen_height,
+    ) {
+        Ok(floor) => Some(floor),
+        Err(e) => {
+            println!("Floor casting disabled: {}", e);
+            None
+        }
+    };
+    // --sky <r,g,b> paints the sky one colour, --sky "atlas <x,y,width,height>"
+    // wraps that section of player_sheet.png around the horizon instead
+    if let (Some(floor), Some(sky)) = (
+        renderer.floor.as_mut(),
+        arg("--sky").and_then(|sky| Sky::parse(&sky)),
+    ) {
+        floor.sky = sky;
+    }
 
     rl.set_target_fps(FPS);
 
@@ -73,3 +165,51 @@ fn main() -> io::Result<()> {
 
     Ok(())
 }
+
+fn invalid_data(e: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+// Casts full views on a large map scattered with stone, single threaded and
+// on every core, at a few resolutions and view distances
+fn bench_rays() {
+    const SIZE: usize = 4096;
+    const FRAMES: usize = 200;
+
+    let mut world = World::new(SIZE, SIZE);
+    for y in 0..SIZE as i32 {
+        for x in 0..SIZE as i32 {
+            if terrain::hash(1, x, y) < u64::MAX / 20 {
+                world.set_tile(x, y, world::Blocks::STONE);
+            }
+        }
+    }
+    let tile_size = world.tile_size as f32;
+    let centre = Vector2::new(SIZE as f32 * tile_size / 2.0, SIZE as f32 * tile_size / 2.0);
+    println!("{}x{} tiles, {} frames per run", SIZE, SIZE, FRAMES);
+
+    for num_rays in [1024, 4096] {
+        for view_distance in [16.0, 128.0] {
+            for (name, mut batch) in [
+                ("1 thread", my_ray::RayBatch::with_threads(1)),
+                ("all cores", my_ray::RayBatch::new()),
+            ] {
+                let start = std::time::Instant::now();
+                for frame in 0..FRAMES {
+                    let angle = frame as f32 * 360.0 / FRAMES as f32;
+                    let max_distance = view_distance * tile_size;
+                    batch.cast(centre, angle, 60.0, num_rays, max_distance, &world);
+                }
+                let elapsed = start.elapsed().as_secs_f64();
+                println!(
+                    "{:>5} rays, {:>3} tiles, {:>9}: {:>7.3} ms/frame, {:>6.2} Mrays/s",
+                    num_rays,
+                    view_distance,
+                    name,
+                    elapsed * 1000.0 / FRAMES as f64,
+                    (num_rays * FRAMES) as f64 / elapsed / 1e6
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,504 @@
+use crate::save::{self, SaveError};
+use crate::tiles::TileRegistry;
+use crate::world::{Blocks, World};
+use std::fmt;
+use std::fs;
+use std::io;
+
+// Terrain as a PNG image or a grid of text, so maps can be drawn in an image
+// editor or written inline. One pixel or character is one tile, the top left
+// one is (0, 0). None of this needs a window.
+//
+// Everything else about the world goes along as .cade bytes, in a zTXt chunk
+// of the png or a `<map>.cade` file next to the text map, so maps round trip
+// losslessly. On load the map's terrain is laid over that world, which keeps
+// edits made to the image or text. Maps without it, like hand written ones,
+// load as terrain only, bounded at (0, 0).
+
+type Rgb = (u8, u8, u8);
+
+// Keyword of the png text chunk holding the world
+const WORLD_KEYWORD: &str = "cade";
+
+#[derive(Debug)]
+pub enum MapError {
+    Io(io::Error),
+    PngDecode(png::DecodingError),
+    PngEncode(png::EncodingError),
+    UnknownColor {
+        x: usize,
+        y: usize,
+        color: Rgb,
+    },
+    UnknownGlyph {
+        x: usize,
+        y: usize,
+        glyph: char,
+    },
+    // The tile can't be written because nothing maps to it
+    Unmapped(Blocks),
+    // Every line of a text map has to be the same width
+    Ragged {
+        line: usize,
+        expected: usize,
+        found: usize,
+    },
+    // The world saved with the map couldn't be read
+    Save(SaveError),
+    BadWorldText,
+    // The map was resized after its world was saved
+    SizeMismatch {
+        map: (usize, usize),
+        world: (usize, usize),
+    },
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapError::Io(e) => write!(f, "io error: {}", e),
+            MapError::PngDecode(e) => write!(f, "couldn't read png: {}", e),
+            MapError::PngEncode(e) => write!(f, "couldn't write png: {}", e),
+            MapError::UnknownColor { x, y, color } => {
+                write!(f, "no tile for colour {:?} at ({}, {})", color, x, y)
+            }
+            MapError::UnknownGlyph { x, y, glyph } => {
+                write!(f, "no tile for {:?} at ({}, {})", glyph, x, y)
+            }
+            MapError::Unmapped(block) => write!(f, "tile {} has no colour or glyph", block.0),
+            MapError::Ragged {
+                line,
+                expected,
+                found,
+            } => write!(
+                f,
+                "line {} is {} tiles wide, expected {}",
+                line, found, expected
+            ),
+            MapError::Save(e) => write!(f, "couldn't read the world saved with the map: {}", e),
+            MapError::BadWorldText => write!(f, "the world saved in the png isn't valid hex"),
+            MapError::SizeMismatch { map, world } => write!(
+                f,
+                "map is {}x{} but the world saved with it is {}x{}",
+                map.0, map.1, world.0, world.1
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MapError {}
+
+impl From<io::Error> for MapError {
+    fn from(e: io::Error) -> Self {
+        MapError::Io(e)
+    }
+}
+
+impl From<SaveError> for MapError {
+    fn from(e: SaveError) -> Self {
+        MapError::Save(e)
+    }
+}
+
+impl From<png::DecodingError> for MapError {
+    fn from(e: png::DecodingError) -> Self {
+        MapError::PngDecode(e)
+    }
+}
+
+impl From<png::EncodingError> for MapError {
+    fn from(e: png::EncodingError) -> Self {
+        MapError::PngEncode(e)
+    }
+}
+
+// Which pixel colour stands for which tile. Every colour maps to exactly one
+// tile and back, so images round trip without losing anything.
+#[derive(Clone, Debug, Default)]
+pub struct ColorMap {
+    colors: Vec<(Rgb, Blocks)>,
+}
+
+impl ColorMap {
+    // Uses each prototype's minimap colour
+    pub fn from_registry(tiles: &TileRegistry) -> Self {
+        let mut map = ColorMap::default();
+        for prototype in tiles.iter() {
+            map.insert(prototype.minimap_color, Blocks(prototype.id));
+        }
+        map
+    }
+
+    // Replaces any mapping that used the colour or the tile
+    pub fn insert(&mut self, color: Rgb, block: Blocks) {
+        self.colors.retain(|&(c, b)| c != color && b != block);
+        self.colors.push((color, block));
+    }
+
+    pub fn block(&self, color: Rgb) -> Option<Blocks> {
+        self.colors
+            .iter()
+            .find(|&&(c, _)| c == color)
+            .map(|&(_, b)| b)
+    }
+
+    pub fn color(&self, block: Blocks) -> Option<Rgb> {
+        self.colors
+            .iter()
+            .find(|&&(_, b)| b == block)
+            .map(|&(c, _)| c)
+    }
+}
+
+// Unbounded worlds are written from the rectangle of chunks they've loaded
+fn area(world: &World) -> (i32, i32, usize, usize) {
+    world.bounds().unwrap_or((0, 0, 0, 0))
+}
+
+// The text map's world is kept in this file
+pub fn sidecar_name(file_name: &str) -> String {
+    format!("{}.cade", file_name)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    let digits = text
+        .chars()
+        .map(|c| c.to_digit(16).map(|digit| digit as u8))
+        .collect::<Option<Vec<u8>>>()?;
+    let pairs = digits.chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return None;
+    }
+    Some(pairs.map(|pair| pair[0] << 4 | pair[1]).collect())
+}
+
+// Lays a map's terrain over the world that was saved with it. Tiles in chunks
+// the world never loaded were written as grass and are left unloaded.
+pub fn with_saved_world(map: World, saved: &[u8]) -> Result<World, MapError> {
+    let (mut world, _, _) = save::decode(saved)?;
+    let (origin_x, origin_y, width, height) = area(&world);
+    let (_, _, map_width, map_height) = area(&map);
+    if (map_width, map_height) != (width, height) {
+        return Err(MapError::SizeMismatch {
+            map: (map_width, map_height),
+            world: (width, height),
+        });
+    }
+
+    world.tiles = map.tiles.clone();
+    for y in 0..height as i32 {
+        for x in 0..width as i32 {
+            let (world_x, world_y) = (origin_x + x, origin_y + y);
+            if let (Some(_), Some(block)) = (world.get_tile(world_x, world_y), map.get_tile(x, y)) {
+                world.set_tile(world_x, world_y, block);
+            }
+        }
+    }
+    Ok(world)
+}
+
+pub fn encode_png(world: &World, colors: &ColorMap) -> Result<Vec<u8>, MapError> {
+    let (origin_x, origin_y, width, height) = area(world);
+
+    let mut pixels = Vec::with_capacity(width * height * 3);
+    for y in origin_y..origin_y + height as i32 {
+        for x in origin_x..origin_x + width as i32 {
+            let block = world.get_tile(x, y).unwrap_or(Blocks::GRASS);
+            let (r, g, b) = colors.color(block).ok_or(MapError::Unmapped(block))?;
+            pixels.extend_from_slice(&[r, g, b]);
+        }
+    }
+
+    let mut bytes = Vec::new();
+    let mut encoder = png::Encoder::new(&mut bytes, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.add_ztxt_chunk(
+        WORLD_KEYWORD.to_string(),
+        to_hex(&save::encode(world, None)),
+    )?;
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pixels)?;
+    writer.finish()?;
+    Ok(bytes)
+}
+
+// Any png works, alpha is ignored and everything is read as 8 bit colour. The
+// world takes the registry's tiles and whatever else the png has saved.
+pub fn decode_png(
+    bytes: &[u8],
+    colors: &ColorMap,
+    tiles: &TileRegistry,
+) -> Result<World, MapError> {
+    let mut decoder = png::Decoder::new(bytes);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer)?;
+    let saved = reader
+        .info()
+        .compressed_latin1_text
+        .iter()
+        .find(|chunk| chunk.keyword == WORLD_KEYWORD)
+        .map(|chunk| chunk.get_text())
+        .transpose()?;
+
+    let (width, height) = (info.width as usize, info.height as usize);
+    let channels = info.color_type.samples();
+    let mut world = World::new(width, height);
+    world.tiles = tiles.clone();
+    for y in 0..height {
+        let row = &buffer[y * info.line_size..];
+        for x in 0..width {
+            let pixel = &row[x * channels..];
+            let color = if channels < 3 {
+                (pixel[0], pixel[0], pixel[0])
+            } else {
+                (pixel[0], pixel[1], pixel[2])
+            };
+            let block = colors
+                .block(color)
+                .ok_or(MapError::UnknownColor { x, y, color })?;
+            world.set_tile(x as i32, y as i32, block);
+        }
+    }
+
+    match saved {
+        Some(text) => with_saved_world(world, &from_hex(&text).ok_or(MapError::BadWorldText)?),
+        None => Ok(world),
+    }
+}
+
+// One line per row of tiles, using each prototype's glyph
+pub fn encode_ascii(world: &World) -> Result<String, MapError> {
+    let (origin_x, origin_y, width, height) = area(world);
+
+    let mut text = String::with_capacity((width + 1) * height);
+    for y in origin_y..origin_y + height as i32 {
+        for x in origin_x..origin_x + width as i32 {
+            let block = world.get_tile(x, y).unwrap_or(Blocks::GRASS);
+            if world.tiles.block(block.to_u8()).is_none() {
+                return Err(MapError::Unmapped(block));
+            }
+            text.push(world.tiles.get(block).glyph);
+        }
+        text.push('\n');
+    }
+    Ok(text)
+}
+
+// Trailing blank lines are ignored, the world takes the registry's tiles. Only
+// terrain is read, see load_ascii for the rest.
+pub fn decode_ascii(text: &str, tiles: &TileRegistry) -> Result<World, MapError> {
+    let lines: Vec<&str> = text.trim_end_matches(['\n', '\r']).lines().collect();
+    let width = lines.first().map_or(0, |line| line.chars().count());
+
+    let mut world = World::new(width, lines.len());
+    world.tiles = tiles.clone();
+    for (y, line) in lines.iter().enumerate() {
+        let found = line.chars().count();
+        if found != width {
+            return Err(MapError::Ragged {
+                line: y + 1,
+                expected: width,
+                found,
+            });
+        }
+
+        for (x, glyph) in line.chars().enumerate() {
+            let block = tiles
+                .by_glyph(glyph)
+                .ok_or(MapError::UnknownGlyph { x, y, glyph })?;
+            world.set_tile(x as i32, y as i32, block);
+        }
+    }
+
+    Ok(world)
+}
+
+pub fn save_png(world: &World, file_name: &str, colors: &ColorMap) -> Result<(), MapError> {
+    save::write_atomic(file_name, &encode_png(world, colors)?)?;
+    Ok(())
+}
+
+pub fn load_png(
+    file_name: &str,
+    colors: &ColorMap,
+    tiles: &TileRegistry,
+) -> Result<World, MapError> {
+    decode_png(&fs::read(file_name)?, colors, tiles)
+}
+
+pub fn save_ascii(world: &World, file_name: &str) -> Result<(), MapError> {
+    save::write_atomic(file_name, encode_ascii(world)?.as_bytes())?;
+    save::write_atomic(&sidecar_name(file_name), &save::encode(world, None))?;
+    Ok(())
+}
+
+// Text maps without a .cade file next to them are terrain only
+pub fn load_ascii(file_name: &str, tiles: &TileRegistry) -> Result<World, MapError> {
+    let map = decode_ascii(&fs::read_to_string(file_name)?, tiles)?;
+    match fs::read(sidecar_name(file_name)) {
+        Ok(saved) => with_saved_world(map, &saved),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(map),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entity::{Direction, EntityKind};
+    use crate::resource::{Resource, ResourceTile};
+    use crate::terrain::TerrainSettings;
+
+    // Every tile the registry knows about, plus some ore and a building
+    fn sample_world() -> World {
+        let mut world = World::new(37, 21);
+        let blocks: Vec<Blocks> = world
+            .tiles
+            .iter()
+            .map(|prototype| Blocks(prototype.id))
+            .collect();
+        for y in 0..21 {
+            for x in 0..37 {
+                let block = blocks[(x * 7 + y * 3) as usize % blocks.len()];
+                world.set_tile(x, y, block);
+            }
+        }
+        world.set_tile(1, 1, Blocks::GRASS);
+        world.set_resource(1, 1, Some(ResourceTile::new(Resource::Iron, 50)));
+        for (x, y) in [(10, 10), (11, 10), (10, 11), (11, 11)] {
+            world.set_tile(x, y, Blocks::GRASS);
+        }
+        world
+            .place_entity(EntityKind::Chest, 10, 10, Direction::North)
+            .unwrap();
+        world
+    }
+
+    fn assert_same_terrain(a: &World, b: &World) {
+        assert_eq!(a.bounds(), b.bounds());
+        let (origin_x, origin_y, width, height) = a.bounds().unwrap();
+        for y in origin_y..origin_y + height as i32 {
+            for x in origin_x..origin_x + width as i32 {
+                assert_eq!(a.get_tile(x, y), b.get_tile(x, y), "tile ({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn png_round_trip() {
+        let world = sample_world();
+        let colors = ColorMap::from_registry(&world.tiles);
+        let bytes = encode_png(&world, &colors).unwrap();
+        let loaded = decode_png(&bytes, &colors, &world.tiles).unwrap();
+        assert_same_terrain(&world, &loaded);
+        assert_eq!(encode_png(&loaded, &colors).unwrap(), bytes);
+    }
+
+    #[test]
+    fn ascii_round_trip() {
+        let world = sample_world();
+        let text = encode_ascii(&world).unwrap();
+        let loaded = decode_ascii(&text, &world.tiles).unwrap();
+        assert_same_terrain(&world, &loaded);
+        assert_eq!(encode_ascii(&loaded).unwrap(), text);
+    }
+
+    // A map gives back the same .cade bytes it was made from, for bounded
+    // worlds and for unbounded ones with gaps between their chunks
+    #[test]
+    fn maps_keep_the_whole_world() {
+        let mut generated = World::generate(3, TerrainSettings::default());
+        generated.set_tile(-5, -5, Blocks::STONE);
+        generated.set_tile(40, 3, Blocks::GRASS);
+        generated.set_resource(40, 3, Some(ResourceTile::new(Resource::Iron, 7)));
+        generated.explore(40, 3);
+
+        let file = std::env::temp_dir().join(format!("map_{}.txt", std::process::id()));
+        let file_name = file.to_str().unwrap();
+        for world in [sample_world(), generated] {
+            let bytes = save::encode(&world, None);
+            let colors = ColorMap::from_registry(&world.tiles);
+
+            let from_png = decode_png(&encode_png(&world, &colors).unwrap(), &colors, &world.tiles);
+            assert_eq!(save::encode(&from_png.unwrap(), None), bytes);
+
+            save_ascii(&world, file_name).unwrap();
+            let from_ascii = load_ascii(file_name, &world.tiles).unwrap();
+            assert_eq!(save::encode(&from_ascii, None), bytes);
+        }
+        fs::remove_file(file_name).unwrap();
+        fs::remove_file(sidecar_name(file_name)).unwrap();
+    }
+
+    // Terrain edited in the map wins over the saved world, a resized map
+    // doesn't fit it any more
+    #[test]
+    fn edited_maps() {
+        let world = sample_world();
+        let saved = save::encode(&world, None);
+
+        let mut text = encode_ascii(&world).unwrap();
+        let stone = world.tiles.get(Blocks::STONE).glyph;
+        text.replace_range(0..1, &stone.to_string());
+        let edited = with_saved_world(decode_ascii(&text, &world.tiles).unwrap(), &saved).unwrap();
+        assert_eq!(edited.get_tile(0, 0), Some(Blocks::STONE));
+        assert_eq!(edited.get_resource(1, 1), world.get_resource(1, 1));
+        assert_eq!(edited.entities.iter().count(), 1);
+
+        let terrain_only = decode_ascii(&text, &world.tiles).unwrap();
+        assert_eq!(terrain_only.entities.iter().count(), 0);
+
+        let cropped: String = text
+            .lines()
+            .skip(1)
+            .map(|line| format!("{}\n", line))
+            .collect();
+        assert!(matches!(
+            with_saved_world(decode_ascii(&cropped, &world.tiles).unwrap(), &saved),
+            Err(MapError::SizeMismatch {
+                map: (37, 20),
+                world: (37, 21)
+            })
+        ));
+    }
+
+    #[test]
+    fn unknown_glyph_and_ragged_lines() {
+        let tiles = TileRegistry::default();
+        assert!(matches!(
+            decode_ascii("..\n.X\n", &tiles),
+            Err(MapError::UnknownGlyph {
+                x: 1,
+                y: 1,
+                glyph: 'X'
+            })
+        ));
+        assert!(matches!(
+            decode_ascii("...\n..\n", &tiles),
+            Err(MapError::Ragged {
+                line: 2,
+                expected: 3,
+                found: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn unknown_color() {
+        let world = sample_world();
+        let mut colors = ColorMap::from_registry(&world.tiles);
+        let bytes = encode_png(&world, &colors).unwrap();
+        colors.insert((1, 2, 3), Blocks::STONE);
+        assert!(matches!(
+            decode_png(&bytes, &colors, &world.tiles),
+            Err(MapError::UnknownColor { .. })
+        ));
+    }
+}
@@ -1,174 +1,699 @@
-use crate::world::Blocks;
-use crate::world::World;
+use crate::entity::EntityId;
+use crate::world::{Blocks, World};
 use raylib::prelude::*;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
 
-// Casts a number of rays spread out from the player
-// Inputs: position, number of rays, angle to be cast (fov)
-// Outputs: Array of distances and hitpoints for each ray
+// Which side of a tile a ray hit
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Face {
+    North,
+    South,
+    East,
+    West,
+}
+
+// What stopped a ray
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum HitKind {
+    Block(Blocks),
+    Entity(EntityId),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RayHit {
+    pub tile_x: i32,
+    pub tile_y: i32,
+    pub kind: HitKind,
+    pub face: Face,
+    // From the start of the ray, in world space
+    pub distance: f32,
+    pub point: Vector2,
+    // Texture coordinate along the face in 0..1, left to right as seen by the ray
+    pub u: f32,
+}
+
+// One column of the player's view
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ViewRay {
+    // Distance to the hit, corrected for the fish eye effect
+    pub distance: f32,
+    // Where the ray stopped, in world space
+    pub end: Vector2,
+    pub hit: Option<RayHit>,
+}
+
+// Angle of a point across the view from its centre, in degrees. `across`
+// runs 0..1 from the left edge of the view to the right. Rays are spread
+// evenly over a flat screen rather than evenly in angle, so straight walls
+// stay straight at any fov.
+pub fn view_angle(across: f32, fov: f32) -> f32 {
+    ((2.0 * across - 1.0) * (fov / 2.0).to_radians().tan())
+        .atan()
+        .to_degrees()
+}
+
+// The inverse of view_angle
+pub fn view_across(angle: f32, fov: f32) -> f32 {
+    (angle.to_radians().tan() / (fov / 2.0).to_radians().tan() + 1.0) / 2.0
+}
 
-pub fn cast_fov(
+// The i-th of num_rays rays across the view
+fn view_ray(
     pos: Vector2,
     player_angle: f32,
-    num_rays: i32,
+    fov: f32,
+    i: usize,
+    num_rays: usize,
+    max_distance: f32,
     world: &World,
-) -> (Vec<f32>, Vec<Vector2>) {
-    let mut distances = Vec::new();
-    let mut hit_positions = Vec::new();
-    let start_angle = player_angle - (num_rays / 2) as f32;
-
-    for i in 0..num_rays {
-        let angle = start_angle + i as f32;
-        let (mut distance, hit_pos) = raycast_dda(pos, angle, world);
-        // fix the fish eye effect
-        let ca = player_angle - angle;
-        distance *= ca.to_radians().cos();
-
-        distances.push(distance);
-        hit_positions.push(hit_pos);
-    }
-
-    (distances, hit_positions)
-}
-
-// Takes the starting point and angle, and casts a ray into the world
-// Inputs: Starting point, angle, world
-// Outputs: distance of the ray, hit position
-pub fn raycast_dda(start: Vector2, angle: f32, world: &World) -> (f32, Vector2) {
-    let ray_angle = angle;
-    let mut ray_pos = Vector2::new(0.0, 0.0);
-
-    let mut y_offset = 0.0;
-    let mut x_offset = 0.0;
-    let mut dof: i32 = 0;
-    let mut mx;
-    let mut my;
-
-    let mut dish = 10000.0;
-    let mut hx = start.x;
-    let mut hy = start.y;
-
-    let mut atan = -1.0 / ray_angle.to_radians().tan();
-
-    if ray_angle.to_radians().sin() < 0.0 {
-        ray_pos.y = (((start.y as i32) >> 6) << 6) as f32 - 0.0001;
-        ray_pos.x = start.x + (start.y - ray_pos.y) * atan;
-        y_offset = -(world.tile_size as f32);
-        x_offset = y_offset * atan;
-    } else if ray_angle.to_radians().sin() > 0.0 {
-        ray_pos.y = (((start.y as i32) >> 6) << 6) as f32 + world.tile_size as f32;
-        ray_pos.x = start.x + (start.y - ray_pos.y) * atan;
-        y_offset = world.tile_size as f32;
-        x_offset = y_offset * atan;
-    } else {
-        ray_pos.x = start.x;
-        ray_pos.y = start.y;
-        dof = 8;
+) -> ViewRay {
+    let angle = player_angle + view_angle((i as f32 + 0.5) / num_rays as f32, fov);
+    // Buildings are drawn as sprites, so the view looks straight through them
+    let hit = cast(
+        pos,
+        angle,
+        world.tile_size as f32,
+        max_distance,
+        false,
+        world,
+        &mut |_, _| {},
+    );
+    let (distance, end) = match hit {
+        Some(hit) => (hit.distance, hit.point),
+        None => {
+            let direction = Vector2::new(angle.to_radians().cos(), angle.to_radians().sin());
+            (max_distance, pos + direction * max_distance)
+        }
+    };
+    // fix the fish eye effect
+    let ca = player_angle - angle;
+    ViewRay {
+        distance: distance * ca.to_radians().cos(),
+        end,
+        hit,
     }
+}
 
-    while dof < 8 {
-        mx = (ray_pos.x as i32) >> 6;
-        my = (ray_pos.y as i32) >> 6;
+// Fewer rays than this per thread aren't worth handing out
+const MIN_RAYS_PER_THREAD: usize = 256;
 
-        if mx >= 0 && my >= 0 && mx < world.data.len() as i32 && my < world.data[0].len() as i32 {
-            if world.data[mx as usize][my as usize] == Blocks::STONE {
-                dof = 8;
-                hx = ray_pos.x;
-                hy = ray_pos.y;
-                dish = dist(start.x, start.y, hx, hy);
-            } else {
-                ray_pos.x -= x_offset;
-                ray_pos.y += y_offset;
-                dof += 1;
+// A run of neighbouring columns for one thread to fill, starting at column
+// `first`. The pointers come from RayBatch::cast, which doesn't return until
+// every job it handed out is done, so they stay valid while a worker uses them.
+struct Job {
+    pos: Vector2,
+    player_angle: f32,
+    fov: f32,
+    num_rays: usize,
+    max_distance: f32,
+    first: usize,
+    rays: *mut ViewRay,
+    len: usize,
+    world: *const World,
+}
+
+// Workers read the world through a shared reference from other threads, so it
+// has to stay Sync. Checked here because Job's raw pointer hides it from the
+// compiler.
+const fn assert_sync<T: Sync>() {}
+const _: () = assert_sync::<World>();
+
+// SAFETY: each job's rays are a run no other job or thread touches, and the
+// world is only read, which is fine from any thread since World is Sync. Both
+// pointers borrow from RayBatch::cast, whose WaitFor guard blocks until every
+// job handed out is finished, even while unwinding, so neither outlives the
+// borrow it came from.
+unsafe impl Send for Job {}
+
+impl Job {
+    fn run(&self) {
+        // SAFETY: the pointers are valid and the rays unaliased for as long
+        // as the job runs, see the Send impl above
+        let (rays, world) = unsafe {
+            (
+                std::slice::from_raw_parts_mut(self.rays, self.len),
+                &*self.world,
+            )
+        };
+        for (i, ray) in rays.iter_mut().enumerate() {
+            *ray = view_ray(
+                self.pos,
+                self.player_angle,
+                self.fov,
+                self.first + i,
+                self.num_rays,
+                self.max_distance,
+                world,
+            );
+        }
+    }
+}
+
+// A thread that runs jobs until its RayBatch is dropped, answering on `done`
+// after each one
+struct Worker {
+    jobs: Sender<Job>,
+    done: Receiver<()>,
+    thread: JoinHandle<()>,
+}
+
+impl Worker {
+    fn spawn() -> Self {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let (finished, done) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            for job in queue {
+                job.run();
+                if finished.send(()).is_err() {
+                    break;
+                }
             }
-        } else {
-            dof = 8;
+        });
+        Worker { jobs, done, thread }
+    }
+}
+
+// Waits for the first `busy` workers to finish their jobs, even if the
+// calling thread panics while they run, since they point into its stack
+struct WaitFor<'a> {
+    workers: &'a [Worker],
+    busy: usize,
+}
+
+impl Drop for WaitFor<'_> {
+    fn drop(&mut self) {
+        // A worker that panicked drops its end of the channel, so recv fails
+        // rather than blocking forever
+        let failed = self.workers[..self.busy]
+            .iter()
+            .filter(|worker| worker.done.recv().is_err())
+            .count();
+        if failed > 0 && !thread::panicking() {
+            panic!("{} ray casting threads panicked", failed);
         }
     }
+}
 
-    dof = 0;
-    let mut disv = 100000.0;
-    let mut vx = start.x;
-    let mut vy = start.y;
+// Casts all of a view's rays at once, split between the calling thread and
+// worker threads that live as long as the batch. The rays are kept between
+// casts so the buffer is only reallocated when the number of rays grows.
+pub struct RayBatch {
+    rays: Vec<ViewRay>,
+    workers: Vec<Worker>,
+}
 
-    // Check Vertical Lines
-    atan = -ray_angle.to_radians().tan();
+impl Default for RayBatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-    if ray_angle.to_radians().cos() < 0.0 {
-        ray_pos.x = (((start.x as i32) >> 6) << 6) as f32 - 0.0001;
-        ray_pos.y = start.y + (start.x - ray_pos.x) * atan;
-        x_offset = -(world.tile_size as f32);
-        y_offset = x_offset * atan;
-    } else if ray_angle.to_radians().cos() > 0.0 {
-        ray_pos.x = (((start.x as i32) >> 6) << 6) as f32 + world.tile_size as f32;
-        ray_pos.y = start.y + (start.x - ray_pos.x) * atan;
-        x_offset = world.tile_size as f32;
-        y_offset = x_offset * atan;
-    } else {
-        ray_pos.x = start.x;
-        ray_pos.y = start.y;
-        dof = 8;
+impl Drop for RayBatch {
+    fn drop(&mut self) {
+        // Closing a worker's queue ends its loop
+        for Worker { jobs, thread, .. } in self.workers.drain(..) {
+            drop(jobs);
+            let _ = thread.join();
+        }
     }
+}
 
-    while dof < 8 {
-        mx = (ray_pos.x as i32) >> 6;
-        my = (ray_pos.y as i32) >> 6;
+impl RayBatch {
+    // Uses as many threads as the machine has cores
+    pub fn new() -> Self {
+        let threads = thread::available_parallelism().map_or(1, |threads| threads.get());
+        Self::with_threads(threads)
+    }
 
-        if mx >= 0 && my >= 0 && mx < world.data.len() as i32 && my < world.data[0].len() as i32 {
-            if world.data[mx as usize][my as usize] == Blocks::STONE {
-                dof = 8;
-                vx = ray_pos.x;
-                vy = ray_pos.y;
-                disv = dist(start.x, start.y, vx, vy);
-            } else {
-                ray_pos.x += x_offset;
-                ray_pos.y -= y_offset;
-                dof += 1;
-            }
-        } else {
-            dof = 8;
+    // `threads` counts the calling thread, so 1 starts no workers
+    pub fn with_threads(threads: usize) -> Self {
+        RayBatch {
+            rays: Vec::new(),
+            workers: (1..threads).map(|_| Worker::spawn()).collect(),
         }
     }
 
-    let final_dist;
+    // The rays from the last cast, left to right
+    pub fn rays(&self) -> &[ViewRay] {
+        &self.rays
+    }
 
-    if disv < dish {
-        ray_pos.x = vx;
-        ray_pos.y = vy;
-        final_dist = disv;
-    } else {
-        ray_pos.x = hx;
-        ray_pos.y = hy;
-        final_dist = dish;
+    // Casts num_rays rays spread out from the player over the fov, one
+    // through the middle of each column of the view
+    // Inputs: position, angle, fov in degrees, number of rays, how far the rays reach, world
+    pub fn cast(
+        &mut self,
+        pos: Vector2,
+        player_angle: f32,
+        fov: f32,
+        num_rays: usize,
+        max_distance: f32,
+        world: &World,
+    ) {
+        let blank = ViewRay {
+            distance: max_distance,
+            end: pos,
+            hit: None,
+        };
+        self.rays.resize(num_rays, blank);
+
+        // Each thread fills its own run of neighbouring columns
+        let threads = (self.workers.len() + 1)
+            .min(num_rays / MIN_RAYS_PER_THREAD)
+            .max(1);
+        let per_thread = num_rays.div_ceil(threads).max(1);
+        let job = |part: usize, rays: &mut [ViewRay]| Job {
+            pos,
+            player_angle,
+            fov,
+            num_rays,
+            max_distance,
+            first: part * per_thread,
+            rays: rays.as_mut_ptr(),
+            len: rays.len(),
+            world,
+        };
+
+        let mut parts = self.rays.chunks_mut(per_thread).enumerate();
+        let Some((_, own)) = parts.next() else {
+            return;
+        };
+        let mut wait = WaitFor {
+            workers: &self.workers,
+            busy: 0,
+        };
+        for (worker, (part, rays)) in self.workers.iter().zip(parts) {
+            worker
+                .jobs
+                .send(job(part, rays))
+                .expect("ray casting thread stopped");
+            wait.busy += 1;
+        }
+        job(0, own).run();
     }
+}
 
-    return (final_dist, ray_pos);
+// Walks the grid from tile to tile along the ray and stops at the first opaque
+// block or building. Returns None if nothing is hit within max_distance or the
+// ray leaves the loaded world. Distances are in world space.
+pub fn raycast(
+    start: Vector2,
+    angle: f32,
+    tile_size: f32,
+    max_distance: f32,
+    world: &World,
+) -> Option<RayHit> {
+    cast(
+        start,
+        angle,
+        tile_size,
+        max_distance,
+        true,
+        world,
+        &mut |_, _| {},
+    )
+}
+
+// Like raycast, but buildings don't stop the ray and `visit` is called with
+// every tile the ray enters, up to and including the one it stops at
+pub fn trace(
+    start: Vector2,
+    angle: f32,
+    tile_size: f32,
+    max_distance: f32,
+    world: &World,
+    mut visit: impl FnMut(i32, i32),
+) -> Option<RayHit> {
+    cast(
+        start,
+        angle,
+        tile_size,
+        max_distance,
+        false,
+        world,
+        &mut visit,
+    )
+}
+
+fn cast(
+    start: Vector2,
+    angle: f32,
+    tile_size: f32,
+    max_distance: f32,
+    hit_entities: bool,
+    world: &World,
+    visit: &mut impl FnMut(i32, i32),
+) -> Option<RayHit> {
+    // Axis aligned angles come out a hair off after to_radians, which would tip
+    // a ray running along a grid line into the row or column beside it
+    let snap = |v: f32| if v.abs() < 1e-6 { 0.0 } else { v };
+    let (dir_x, dir_y) = (
+        snap(angle.to_radians().cos()),
+        snap(angle.to_radians().sin()),
+    );
+
+    // Everything below is in tiles
+    let (start_x, start_y) = (start.x / tile_size, start.y / tile_size);
+    let max_distance = max_distance / tile_size;
+    let mut tile_x = start_x.floor() as i32;
+    let mut tile_y = start_y.floor() as i32;
+
+    // How far along the ray it is from one grid line to the next
+    let delta_x = (1.0 / dir_x).abs();
+    let delta_y = (1.0 / dir_y).abs();
+
+    // How far along the ray the next vertical and horizontal grid lines are
+    let (step_x, mut next_x) = if dir_x < 0.0 {
+        (-1, (start_x - tile_x as f32) * delta_x)
+    } else {
+        (1, (tile_x as f32 + 1.0 - start_x) * delta_x)
+    };
+    let (step_y, mut next_y) = if dir_y < 0.0 {
+        (-1, (start_y - tile_y as f32) * delta_y)
+    } else {
+        (1, (tile_y as f32 + 1.0 - start_y) * delta_y)
+    };
+
+    loop {
+        // Step into whichever neighbouring tile the ray reaches first
+        let (distance, face) = if next_x < next_y {
+            tile_x += step_x;
+            next_x += delta_x;
+            let face = if step_x > 0 { Face::West } else { Face::East };
+            (next_x - delta_x, face)
+        } else {
+            tile_y += step_y;
+            next_y += delta_y;
+            let face = if step_y > 0 { Face::North } else { Face::South };
+            (next_y - delta_y, face)
+        };
+
+        if distance > max_distance {
+            return None;
+        }
+
+        let entity = world.entities.at(tile_x, tile_y).filter(|_| hit_entities);
+        let kind = match entity {
+            Some(entity) => HitKind::Entity(entity.id),
+            None => match world.get_tile(tile_x, tile_y) {
+                Some(block) if world.tiles.is_opaque(block) => HitKind::Block(block),
+                Some(_) => {
+                    visit(tile_x, tile_y);
+                    continue;
+                }
+                // Ran off the edge of the loaded world
+                None => return None,
+            },
+        };
+        visit(tile_x, tile_y);
+
+        let (hit_x, hit_y) = (start_x + dir_x * distance, start_y + dir_y * distance);
+        // Sweeping the view clockwise moves left to right across the screen
+        let u = match face {
+            Face::North => 1.0 - (hit_x - hit_x.floor()),
+            Face::South => hit_x - hit_x.floor(),
+            Face::West => hit_y - hit_y.floor(),
+            Face::East => 1.0 - (hit_y - hit_y.floor()),
+        };
+
+        return Some(RayHit {
+            tile_x,
+            tile_y,
+            kind,
+            face,
+            distance: distance * tile_size,
+            point: Vector2::new(hit_x * tile_size, hit_y * tile_size),
+            u,
+        });
+    }
 }
 
 pub fn dist(ax: f32, ay: f32, bx: f32, by: f32) -> f32 {
     ((bx - ax).powi(2) + (by - ay).powi(2)).sqrt()
 }
 
-pub fn ray_cast_brute_force(start: Vector2, angle: f32, world: &mut World) -> Vector2 {
+pub fn ray_cast_brute_force(start: Vector2, angle: f32, world: &World) -> Vector2 {
     // Start the ray in world space at the player's position
     let mut ray_end = start;
     let step_size: f32 = 2.0;
 
-    // World grid dimensions
-    let grid_width = world.data.len();
-    let grid_height = world.data[0].len();
+    // Keep stepping while the ray is inside the loaded world and not hitting a wall
+    loop {
+        let (tile_x, tile_y) = world.tile_at(ray_end);
+        match world.get_tile(tile_x, tile_y) {
+            Some(block) if world.tiles.is_opaque(block) => break,
+            None => break,
+            Some(_) => {
+                ray_end.x += angle.to_radians().cos() * step_size;
+                ray_end.y += angle.to_radians().sin() * step_size;
+            }
+        }
+    }
+
+    ray_end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terrain;
+
+    // ray_cast_brute_force moves this far per step
+    const STEP: f32 = 2.0;
+    // Float error in the brute force walk, which adds up step after step
+    const EPSILON: f32 = 0.5;
+
+    // A number in 0..1 that's always the same for the same seed and index
+    fn random(seed: u64, i: i32) -> f32 {
+        (terrain::hash(seed, i, 0) >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    // A walled in world with a random mix of every block inside
+    fn random_world(seed: u64, width: usize, height: usize, tile_size: usize) -> World {
+        let mut world = World::new(width, height);
+        world.tile_size = tile_size;
+        let blocks = [Blocks::GRASS, Blocks::STONE, Blocks::WATER, Blocks::SAND];
+        for y in 0..height as i32 {
+            for x in 0..width as i32 {
+                let edge = x == 0 || y == 0 || x == width as i32 - 1 || y == height as i32 - 1;
+                let block = if edge {
+                    Blocks::STONE
+                } else {
+                    blocks[(terrain::hash(seed, x, y) % 8).min(3) as usize]
+                };
+                world.set_tile(x, y, block);
+            }
+        }
+        world
+    }
+
+    // How far along the ray it leaves a tile
+    fn exit_distance(start: Vector2, angle: f32, tile_x: i32, tile_y: i32, tile_size: f32) -> f32 {
+        let (dir_x, dir_y) = (angle.to_radians().cos(), angle.to_radians().sin());
+        let exit = |start: f32, dir: f32, tile: i32| {
+            if dir > 0.0 {
+                ((tile + 1) as f32 * tile_size - start) / dir
+            } else if dir < 0.0 {
+                (tile as f32 * tile_size - start) / dir
+            } else {
+                f32::INFINITY
+            }
+        };
+        exit(start.x, dir_x, tile_x).min(exit(start.y, dir_y, tile_y))
+    }
+
+    // Fires both raycasters and checks they stop in the same place
+    fn check(start: Vector2, angle: f32, world: &World) {
+        let tile_size = world.tile_size as f32;
+        let hit = raycast(start, angle, tile_size, f32::MAX, world)
+            .unwrap_or_else(|| panic!("no hit from {:?} at {}", start, angle));
+        let end = ray_cast_brute_force(start, angle, world);
+        let brute_distance = dist(start.x, start.y, end.x, end.y);
+
+        // The hit is on the face of an opaque tile
+        let block = world.get_tile(hit.tile_x, hit.tile_y).unwrap();
+        assert!(world.tiles.is_opaque(block));
+        let (face_x, face_y) = match hit.face {
+            Face::West => (Some(hit.tile_x), None),
+            Face::East => (Some(hit.tile_x + 1), None),
+            Face::North => (None, Some(hit.tile_y)),
+            Face::South => (None, Some(hit.tile_y + 1)),
+        };
+        if let Some(x) = face_x {
+            assert!((hit.point.x - x as f32 * tile_size).abs() < EPSILON);
+        }
+        if let Some(y) = face_y {
+            assert!((hit.point.y - y as f32 * tile_size).abs() < EPSILON);
+        }
+        assert!((0.0..=1.0).contains(&hit.u));
+
+        // Brute force only notices a wall once it has stepped inside, so it
+        // stops up to one step later. It can step straight over the corner of
+        // a tile the ray barely clips, then it's fine for it to go further.
+        let gap = brute_distance - hit.distance;
+        assert!(
+            gap > -EPSILON,
+            "brute force stopped before the hit: {:?} at {}, dda {}, brute {}",
+            start,
+            angle,
+            hit.distance,
+            brute_distance
+        );
+        if gap > STEP + EPSILON {
+            let clipped =
+                exit_distance(start, angle, hit.tile_x, hit.tile_y, tile_size) - hit.distance;
+            assert!(
+                clipped < STEP + EPSILON,
+                "{:?} at {}: dda {}, brute {}",
+                start,
+                angle,
+                hit.distance,
+                brute_distance
+            );
+        }
+    }
+
+    // Somewhere open in the world to cast from
+    fn random_start(seed: u64, i: i32, world: &World) -> Vector2 {
+        let (_, _, width, height) = world.bounds().unwrap();
+        let tile_size = world.tile_size as f32;
+        let mut n = i * 1000;
+        loop {
+            let x = 1.0 + random(seed, n) * (width - 2) as f32;
+            let y = 1.0 + random(seed, n + 1) * (height - 2) as f32;
+            // Every few starts sits on a grid line or corner
+            let (x, y) = match n % 4 {
+                1 => (x.floor(), y),
+                2 => (x, y.floor()),
+                3 => (x.floor(), y.floor()),
+                _ => (x, y),
+            };
+            n += 2;
+            let start = Vector2::new(x * tile_size, y * tile_size);
+            if !open_around(start, world) {
+                continue;
+            }
+            return start;
+        }
+    }
+
+    // Whether every tile touching a point is see-through, so neither
+    // raycaster starts out inside a wall
+    fn open_around(point: Vector2, world: &World) -> bool {
+        let tile_size = world.tile_size as f32;
+        let (x, y) = (point.x / tile_size, point.y / tile_size);
+        [x.floor(), (x - 1e-3).floor()].iter().all(|&tile_x| {
+            [y.floor(), (y - 1e-3).floor()].iter().all(|&tile_y| {
+                world
+                    .get_tile(tile_x as i32, tile_y as i32)
+                    .is_some_and(|block| !world.tiles.is_opaque(block))
+            })
+        })
+    }
+
+    #[test]
+    fn agrees_with_brute_force_on_random_worlds() {
+        for seed in 0..12u64 {
+            let tile_size = [64, 32, 7][seed as usize % 3];
+            let world = random_world(seed, 24 + seed as usize, 20, tile_size);
+            for i in 0..16 {
+                let start = random_start(seed, i, &world);
+                for a in 0..48 {
+                    let angle = a as f32 * 7.5 + random(seed + 100, i * 48 + a) * 7.5;
+                    check(start, angle, &world);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn agrees_with_brute_force_along_the_axes() {
+        for seed in 0..6u64 {
+            let world = random_world(seed + 50, 30, 30, 64);
+            for i in 0..32 {
+                let start = random_start(seed, i, &world);
+                for angle in [0.0, 90.0, 180.0, 270.0, 360.0, -90.0] {
+                    check(start, angle, &world);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn agrees_with_brute_force_from_grid_lines() {
+        let world = random_world(7, 32, 32, 64);
+        for tile_x in 1..31 {
+            for tile_y in 1..31 {
+                for (x, y) in [(0.0, 0.0), (0.0, 0.5), (0.5, 0.0)] {
+                    let start =
+                        Vector2::new((tile_x as f32 + x) * 64.0, (tile_y as f32 + y) * 64.0);
+                    if !open_around(start, &world) {
+                        continue;
+                    }
+                    for angle in [0.0, 45.0, 90.0, 135.0, 180.0, 225.0, 270.0, 315.0, 10.0] {
+                        check(start, angle, &world);
+                    }
+                }
+            }
+        }
+    }
 
-    // Check if the ray is within bounds and not hitting a wall
-    while ray_end.x >= 0.0
-        && ray_end.y >= 0.0
-        && ((ray_end.x / world.tile_size as f32) as usize) < grid_width
-        && ((ray_end.y / world.tile_size as f32) as usize) < grid_height
-        && world.data[(ray_end.x / world.tile_size as f32) as usize]
-            [(ray_end.y / world.tile_size as f32) as usize]
-            != Blocks::STONE
-    {
-        ray_end.x += angle.to_radians().cos() * step_size;
-        ray_end.y += angle.to_radians().sin() * step_size;
+    // A single stone in an open room, seen from each side
+    #[test]
+    fn hits_the_facing_side_along_the_axes() {
+        let mut world = random_world(0, 11, 11, 64);
+        for y in 1..10 {
+            for x in 1..10 {
+                world.set_tile(x, y, Blocks::GRASS);
+            }
+        }
+        world.set_tile(5, 5, Blocks::STONE);
+
+        let centre = |x: f32, y: f32| Vector2::new(x * 64.0, y * 64.0);
+        for (start, angle, face) in [
+            (centre(2.5, 5.5), 0.0, Face::West),
+            (centre(8.5, 5.5), 180.0, Face::East),
+            (centre(5.5, 2.5), 90.0, Face::North),
+            (centre(5.5, 8.5), 270.0, Face::South),
+        ] {
+            let hit = raycast(start, angle, 64.0, f32::MAX, &world).unwrap();
+            assert_eq!((hit.tile_x, hit.tile_y, hit.face), (5, 5, face));
+            assert!((hit.distance - 2.5 * 64.0).abs() < 1e-2);
+            assert!((hit.u - 0.5).abs() < 1e-3);
+            check(start, angle, &world);
+        }
+
+        // Starting right on the stone's face hits it straight away
+        let hit = raycast(centre(6.0, 5.5), 180.0, 64.0, f32::MAX, &world).unwrap();
+        assert_eq!((hit.tile_x, hit.face), (5, Face::East));
+        assert!(hit.distance.abs() < 1e-3);
     }
 
-    return ray_end;
+    #[test]
+    fn stops_at_max_distance_and_the_edge_of_the_world() {
+        let mut world = World::new(10, 10);
+        world.set_tile(9, 5, Blocks::STONE);
+        let start = Vector2::new(2.5 * 64.0, 5.5 * 64.0);
+        assert!(raycast(start, 0.0, 64.0, 6.0 * 64.0, &world).is_none());
+        assert!(raycast(start, 0.0, 64.0, 7.0 * 64.0, &world).is_some());
+        // Nothing to the west, the ray runs off the map
+        assert!(raycast(start, 180.0, 64.0, f32::MAX, &world).is_none());
+    }
+
+    // The workers carry on from one cast to the next, however the rays split
+    #[test]
+    fn threads_cast_the_same_rays_as_one() {
+        let world = random_world(5, 64, 64, 32);
+        let start = random_start(5, 0, &world);
+        let mut single = RayBatch::with_threads(1);
+        let mut batch = RayBatch::with_threads(4);
+        for (frame, num_rays) in [3000, 1000, 0, 255, 1024, 4001].into_iter().enumerate() {
+            let angle = frame as f32 * 50.0;
+            single.cast(start, angle, 75.0, num_rays, 40.0 * 32.0, &world);
+            batch.cast(start, angle, 75.0, num_rays, 40.0 * 32.0, &world);
+            assert_eq!(batch.rays().len(), num_rays);
+            assert_eq!(batch.rays(), single.rays());
+        }
+    }
 }
@@ -1,7 +1,12 @@
+use crate::chunk::ChunkPos;
+use crate::collision;
+use crate::entity::{Blueprint, EntityId};
+use crate::fps::{self, FloorCaster};
 use crate::my_ray;
 use crate::player;
 use crate::render;
 use crate::state::*;
+use crate::vision::Vision;
 use crate::world;
 use crate::world::*;
 use raylib::ffi;
@@ -12,47 +17,318 @@ use std::os::raw::c_int;
 // 1. set discrete values for zoom
 // 2. Only draw rays when player moves to put less stress on the cpu
 
-const NUM_RAYS: i32 = 60; // This is also the fov
+const FOV: f32 = 60.0; // In degrees
+pub const VIEW_DISTANCE: f32 = 16.0; // In tiles
+const REACH: f32 = 3.0; // In tiles, how far away things can be mined or built
+const RADIUS: f32 = 0.25; // In tiles, how close the player gets to walls
+const WALK_SPEED: f32 = 3.0; // In tiles per second on ground with a walk_speed of 1
+const ACCELERATION: f32 = 24.0; // In tiles per second squared
+const FRICTION: f32 = 16.0; // In tiles per second squared, slowing down with no key held
+const TURN_SPEED: f32 = 200.0; // In degrees per second
+const MOUSE_SENSITIVITY: f32 = 0.15; // In degrees per pixel
+const MIN_MOUSE_SENSITIVITY: f32 = 0.01;
+
+// How far out from the origin, in tiles, open ground to spawn on is looked for
+const SPAWN_SEARCH: i32 = 256;
+
+// Longest frame movement is worked out for, so a stall doesn't fling the player
+const MAX_DELTA: f32 = 0.1; // In seconds
+
+// Rays cast until set_resolution is called, one per column of the view
+const DEFAULT_RAYS: usize = 60;
+const NS_FACE_SHADE: f32 = 0.7;
+const HIGHLIGHT: Color = Color::YELLOW;
+
+// What the crosshair is on, if it's within reach
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Target {
+    // The ground tile right in front of the player, where buildings go and
+    // ore is mined from. The crosshair sits on the horizon, so this is the
+    // tile the player faces rather than one under the crosshair.
+    Ground { x: i32, y: i32 },
+    Wall { x: i32, y: i32, face: my_ray::Face },
+    Building(EntityId),
+}
+
+impl Target {
+    // The tiles it covers as (x, y, width, height)
+    fn area(self, world: &World) -> Option<(i32, i32, i32, i32)> {
+        match self {
+            Target::Ground { x, y } | Target::Wall { x, y, .. } => Some((x, y, 1, 1)),
+            Target::Building(id) => world.entities.get(id).map(|entity| {
+                let size = entity.kind.size();
+                (entity.x, entity.y, size, size)
+            }),
+        }
+    }
+}
+
+// How the movement keys work
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Controls {
+    // W and S walk forwards and backwards, A and D turn
+    Tank,
+    // A and D step sideways instead, turning is left to the mouse or arrow keys
+    Strafe,
+}
+
+impl Controls {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "tank" => Some(Controls::Tank),
+            "strafe" => Some(Controls::Strafe),
+            _ => None,
+        }
+    }
+}
+
+// Everything about the player that gets saved
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PlayerState {
+    pub x: f32,
+    pub y: f32,
+    pub angle: f32,
+    // The game clock, which lives in State::ticks
+    pub ticks: u64,
+}
 
 pub struct Player {
     pub pos: Vector2,
-    velocity: f32,
+    // In world units per second
+    velocity: Vector2,
     direction: Vector2, // cos and sin values
     angle: f32,
-    distances: (Vec<f32>, Vec<Vector2>),
+    pub fov: f32,
+    // How many columns the FPS view is split into
+    pub num_rays: usize,
+    rays: my_ray::RayBatch,
+    // What the player can see, the minimap hides the rest
+    pub vision: Vision,
+    pub target: Option<Target>,
+    // The building whose details are shown, opened with E
+    pub open: Option<EntityId>,
+    // What P places in front of the player
+    pub blueprint: Blueprint,
+    pub controls: Controls,
+    // Turns the player with the mouse, the cursor is hidden while it's on
+    pub mouse_look: bool,
+    mouse_sensitivity: f32, // In degrees per pixel
+    cursor_captured: bool,
+    // raylib moves the cursor to the middle of the window when it's captured,
+    // so the next frame's mouse delta is a jump rather than a turn
+    skip_mouse_delta: bool,
+}
+
+// The middle of the open tile nearest the world's origin, one without a wall
+// or a building on it. Chunks are loaded as the search reaches them.
+fn spawn_point(world: &mut World) -> Vector2 {
+    let mut best: Option<(i32, i32, i32)> = None;
+    for ring in 0..=SPAWN_SEARCH {
+        // Every tile further out is at least `ring` tiles away
+        if best.is_some_and(|(distance, _, _)| distance < ring * ring) {
+            break;
+        }
+        // Corners come up twice, which doesn't matter
+        for i in -ring..=ring {
+            for (x, y) in [(i, -ring), (i, ring), (-ring, i), (ring, i)] {
+                if !world.in_bounds(x, y) {
+                    continue;
+                }
+                world.load_chunk(ChunkPos::from_tile(x, y));
+                let distance = x * x + y * y;
+                if !world.is_solid(x, y)
+                    && world.entities.at(x, y).is_none()
+                    && best.is_none_or(|(nearest, _, _)| distance < nearest)
+                {
+                    best = Some((distance, x, y));
+                }
+            }
+        }
+    }
+
+    let (x, y) = match best {
+        Some((_, x, y)) => (x, y),
+        None => {
+            println!("no open ground near the origin, spawning on a wall");
+            (0, 0)
+        }
+    };
+    let tile_size = world.tile_size as f32;
+    Vector2::new((x as f32 + 0.5) * tile_size, (y as f32 + 0.5) * tile_size)
 }
 
 impl Player {
     pub fn new(world: &mut World) -> Self {
-        let pos = Vector2::new(256.0, 256.0);
+        let pos = spawn_point(world);
         let angle = 0.0;
-        Player {
+        let mut player = Player {
             pos,
             angle,
-            velocity: 10.0,
+            velocity: Vector2::zero(),
             direction: Vector2::new(0.0, 0.0),
-            distances: my_ray::cast_fov(pos, angle, NUM_RAYS, world),
+            fov: FOV,
+            num_rays: DEFAULT_RAYS,
+            rays: my_ray::RayBatch::new(),
+            vision: Vision::new(),
+            target: None,
+            open: None,
+            blueprint: Blueprint::default(),
+            controls: Controls::Tank,
+            mouse_look: false,
+            mouse_sensitivity: MOUSE_SENSITIVITY,
+            cursor_captured: false,
+            skip_mouse_delta: false,
+        };
+        player.cast_rays(world);
+        player.look_around(world);
+        player.target = player.look_at(world);
+        player
+    }
+
+    fn cast_rays(&mut self, world: &World) {
+        let max_distance = VIEW_DISTANCE * world.tile_size as f32;
+        self.rays.cast(
+            self.pos,
+            self.angle,
+            self.fov,
+            self.num_rays,
+            max_distance,
+            world,
+        );
+    }
+
+    // Changes the fov and how many rays are spread over it. Casting one ray
+    // per screen column gives the sharpest view.
+    pub fn set_resolution(&mut self, fov: f32, num_rays: usize, world: &World) {
+        self.fov = fov;
+        self.num_rays = num_rays.max(1);
+        self.cast_rays(world);
+    }
+
+    pub fn state(&self, ticks: u64) -> PlayerState {
+        PlayerState {
+            x: self.pos.x,
+            y: self.pos.y,
+            angle: self.angle,
+            ticks,
         }
     }
 
+    pub fn restore(&mut self, state: PlayerState, world: &mut World) {
+        self.pos = Vector2::new(state.x, state.y);
+        self.angle = state.angle;
+        self.cast_rays(world);
+        self.look_around(world);
+    }
+
+    // Follows the crosshair out from the middle of the view
+    fn look_at(&self, world: &World) -> Option<Target> {
+        let tile_size = world.tile_size as f32;
+        let reach = REACH * tile_size;
+        match my_ray::raycast(self.pos, self.angle, tile_size, reach, world) {
+            Some(hit) => Some(match hit.kind {
+                my_ray::HitKind::Block(_) => Target::Wall {
+                    x: hit.tile_x,
+                    y: hit.tile_y,
+                    face: hit.face,
+                },
+                my_ray::HitKind::Entity(id) => Target::Building(id),
+            }),
+            // The first tile the view ray enters past the one underfoot
+            None => {
+                let mut ahead = None;
+                my_ray::trace(self.pos, self.angle, tile_size, reach, world, |x, y| {
+                    ahead = ahead.or(Some((x, y)));
+                });
+                let (x, y) = ahead?;
+                world.get_tile(x, y).map(|_| Target::Ground { x, y })
+            }
+        }
+    }
+
+    // Mining, building and opening whatever the crosshair is on, returns
+    // whether the world changed
+    fn interact(&mut self, world: &mut World) -> bool {
+        let mut changed = false;
+        unsafe {
+            if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_M as c_int) {
+                if let Some(Target::Ground { x, y }) = self.target {
+                    changed |= world.mine_ore(x, y);
+                }
+            }
+
+            if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_B as c_int) {
+                self.blueprint.next_kind();
+            }
+            if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_Q as c_int) {
+                self.blueprint.rotate();
+            }
+            if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_P as c_int) {
+                if let Some(Target::Ground { x, y }) = self.target {
+                    changed |= world.build(self.blueprint, x, y);
+                }
+            }
+            if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_X as c_int) {
+                if let Some(Target::Building(id)) = self.target {
+                    let corner = world.entities.get(id).map(|entity| (entity.x, entity.y));
+                    if let Some((x, y)) = corner {
+                        changed |= world.demolish(x, y);
+                    }
+                }
+            }
+
+            if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_E as c_int) {
+                self.open = match self.target {
+                    Some(Target::Building(id)) if self.open != Some(id) => Some(id),
+                    _ => None,
+                };
+            }
+        }
+        changed
+    }
+
+    // Updates what the player can see and marks it as explored
+    fn look_around(&mut self, world: &mut World) {
+        let max_distance = VIEW_DISTANCE * world.tile_size as f32;
+        self.vision.update(self.pos, max_distance, world);
+    }
+
     pub fn render(
         &self,
         state: &State,
         d: &mut RaylibDrawHandle,
+        texture_atlas: &Texture2D,
+        floor: Option<&mut FloorCaster>,
         camera: &Camera2D,
         world: &World,
     ) {
         if state.view == View::FPS {
-            Self::render_fps(self, state, d, world);
+            Self::render_fps(self, state, d, texture_atlas, floor, world);
         } else {
-            Self::render_minimap(self, d, camera);
+            Self::render_minimap(self, d, camera, world);
         }
+        self.draw_open_building(d, state, world);
     }
 
-    pub fn render_minimap(&self, d: &mut RaylibDrawHandle, camera: &Camera2D) {
+    pub fn render_minimap(&self, d: &mut RaylibDrawHandle, camera: &Camera2D, world: &World) {
+        if let Some((x, y, width, height)) = self.target.and_then(|target| target.area(world)) {
+            let tile_size = world.tile_size as f32;
+            let corner = render::entity_to_screen(
+                Vector2::new(x as f32 * tile_size, y as f32 * tile_size),
+                camera,
+            );
+            let outline = Rectangle {
+                x: corner.x,
+                y: corner.y,
+                width: width as f32 * tile_size * camera.zoom,
+                height: height as f32 * tile_size * camera.zoom,
+            };
+            d.draw_rectangle_lines_ex(outline, 2.0, HIGHLIGHT);
+        }
+
         Self::draw_direction_line(self, d, camera);
-        // Draw the circle at the center of the tile
-        let player_radius = 5.0 * camera.zoom;
+        // Drawn as big as the circle that collides with walls
+        let player_radius = RADIUS * world.tile_size as f32 * camera.zoom;
         d.draw_circle(
             render::entity_to_screen(self.pos, camera).x as i32,
             render::entity_to_screen(self.pos, camera).y as i32,
@@ -60,8 +336,8 @@ impl Player {
             Color::RED,
         );
 
-        for i in 0..self.distances.1.len() {
-            let ray_pos = self.distances.1[i];
+        for ray in self.rays.rays() {
+            let ray_pos = ray.end;
             // Convert the world space positions to screen space for drawing
             let ray_start_screen = render::entity_to_screen(self.pos, camera);
             let ray_end_screen = render::entity_to_screen(ray_pos, camera);
@@ -71,48 +347,262 @@ impl Player {
         }
     }
 
-    pub fn render_fps(&self, state: &State, d: &mut RaylibDrawHandle, world: &World) {
-        let num_rays = self.distances.0.len();
-        let column_width = (state.screen_width / num_rays as i32) as i32;
+    pub fn render_fps(
+        &self,
+        state: &State,
+        d: &mut RaylibDrawHandle,
+        texture_atlas: &Texture2D,
+        floor: Option<&mut FloorCaster>,
+        world: &World,
+    ) {
+        let rays = self.rays.rays();
+        let num_rays = rays.len();
+        // Fractional so the columns always add up to the screen width
+        let column_width = state.screen_width as f32 / num_rays as f32;
+        let max_distance = VIEW_DISTANCE * world.tile_size as f32;
+
+        let view = fps::FpsView {
+            pos: self.pos,
+            angle: self.angle,
+            fov: self.fov,
+            view_width: state.screen_width as f32,
+            screen_height: state.screen_height as f32,
+            max_distance,
+        };
+
+        if let Some(floor) = floor {
+            floor.update(&view, &state.light, world);
+            floor.draw(d, state.screen_width, state.screen_height);
+        }
+
+        for (i, ray) in rays.iter().enumerate() {
+            let Some(hit) = ray.hit else {
+                continue;
+            };
+            // The view looks straight through buildings, see my_ray::view_ray
+            let my_ray::HitKind::Block(block) = hit.kind else {
+                continue;
+            };
 
-        for i in 0..num_rays {
-            let wall_height =
-                (state.screen_height as f32 * world.tile_size as f32) / self.distances.0[i];
-            let x = i as i32 * column_width;
-            let y = ((state.screen_height as f32 - wall_height) / 2.0) as i32;
-            let height = wall_height as i32;
+            let wall_height = view.scale_at(ray.distance) * world.tile_size as f32;
+            let left = (i as f32 * column_width).round();
+            let right = ((i + 1) as f32 * column_width).round();
+            let dest = Rectangle {
+                x: left,
+                y: (state.screen_height as f32 - wall_height) / 2.0,
+                width: right - left,
+                height: wall_height,
+            };
 
-            if self.distances.0[i] < 1000.0 {
-                d.draw_rectangle(x, y, column_width, height, Color::BLUE);
+            // North and south faces are darker so corners stand out, and
+            // everything fades towards the edge of the view distance
+            let face_shade = match hit.face {
+                my_ray::Face::North | my_ray::Face::South => NS_FACE_SHADE,
+                my_ray::Face::East | my_ray::Face::West => 1.0,
+            };
+            let falloff = fps::fog(hit.distance, max_distance);
+            let (x, y) = in_front_of(&hit);
+            let lit = state.light.level(x, y);
+            let brightness = (255.0 * face_shade * falloff * lit) as u8;
+
+            d.draw_texture_pro(
+                texture_atlas,
+                wall_column(block, hit.u, world),
+                dest,
+                Vector2::new(0.0, 0.0),
+                0.0,
+                Color::new(brightness, brightness, brightness, 255),
+            );
+        }
+
+        fps::draw_sprites(
+            d,
+            texture_atlas,
+            &view,
+            rays,
+            &fps::entity_sprites(world, &state.light),
+            world.tile_size as f32,
+        );
+
+        self.draw_target_fps(d, &view, world);
+        let (center_x, center_y) = (state.screen_width / 2, state.screen_height / 2);
+        d.draw_line(center_x - 8, center_y, center_x + 8, center_y, Color::WHITE);
+        d.draw_line(center_x, center_y - 8, center_x, center_y + 8, Color::WHITE);
+    }
+
+    // Outlines the target in the FPS view: the tile on the floor, the face
+    // of the wall or the box around the building's sprite
+    fn draw_target_fps(&self, d: &mut RaylibDrawHandle, view: &fps::FpsView, world: &World) {
+        let Some(target) = self.target else {
+            return;
+        };
+        let Some((x, y, width, height)) = target.area(world) else {
+            return;
+        };
+        let tile_size = world.tile_size as f32;
+        let min = Vector2::new(x as f32 * tile_size, y as f32 * tile_size);
+        let max = Vector2::new(
+            (x + width) as f32 * tile_size,
+            (y + height) as f32 * tile_size,
+        );
+
+        let (min, max, box_height) = match target {
+            Target::Ground { .. } => (min, max, 0.0),
+            Target::Wall { face, .. } => match face {
+                my_ray::Face::West => (min, Vector2::new(min.x, max.y), tile_size),
+                my_ray::Face::East => (Vector2::new(max.x, min.y), max, tile_size),
+                my_ray::Face::North => (min, Vector2::new(max.x, min.y), tile_size),
+                my_ray::Face::South => (Vector2::new(min.x, max.y), max, tile_size),
+            },
+            // As tall as the sprite is wide
+            Target::Building(_) => (min, max, max.x - min.x),
+        };
+        fps::draw_box(d, view, min, max, box_height, tile_size, HIGHLIGHT);
+    }
+
+    // Details of the open building
+    fn draw_open_building(&self, d: &mut RaylibDrawHandle, state: &State, world: &World) {
+        let Some(entity) = self.open.and_then(|id| world.entities.get(id)) else {
+            return;
+        };
+        let text = format!(
+            "{:?} #{} at ({}, {}) facing {:?}",
+            entity.kind, entity.id.0, entity.x, entity.y, entity.direction
+        );
+        let panel = Rectangle {
+            x: 10.0,
+            y: state.screen_height as f32 - 50.0,
+            width: state.screen_width as f32 - 20.0,
+            height: 40.0,
+        };
+        d.draw_rectangle_rec(panel, Color::new(0, 0, 0, 180));
+        d.draw_rectangle_lines_ex(panel, 2.0, HIGHLIGHT);
+        d.draw_text(&text, 20, panel.y as i32 + 10, 20, Color::WHITE);
+    }
+
+    // Hides the cursor and keeps it in the window while mouse-look is on
+    fn capture_cursor(&mut self, capture: bool) {
+        if capture != self.cursor_captured {
+            unsafe {
+                if capture {
+                    ffi::DisableCursor();
+                } else {
+                    ffi::EnableCursor();
+                }
             }
+            self.cursor_captured = capture;
+            self.skip_mouse_delta = capture;
+        }
+    }
+
+    // Never below MIN_MOUSE_SENSITIVITY, so the mouse can't stop turning the
+    // view or turn it backwards
+    pub fn set_mouse_sensitivity(&mut self, sensitivity: f32) {
+        self.mouse_sensitivity = sensitivity.max(MIN_MOUSE_SENSITIVITY);
+    }
+
+    // Speeds up towards the wanted velocity, or slows down to a stop when
+    // there's none, then moves for one frame. Returns whether the player moved.
+    fn walk(&mut self, wanted: Vector2, delta_time: f32, world: &World) -> bool {
+        let tile_size = world.tile_size as f32;
+        let rate = if wanted == Vector2::zero() {
+            FRICTION
+        } else {
+            ACCELERATION
+        };
+        self.velocity = approach(self.velocity, wanted, rate * tile_size * delta_time);
+        if self.velocity == Vector2::zero() || delta_time <= 0.0 {
+            return false;
         }
+
+        let start = self.pos;
+        let radius = RADIUS * tile_size;
+        self.pos = collision::slide(start, self.velocity * delta_time, radius, world);
+        // Whatever a wall stopped is lost, so backing off a wall is immediate
+        self.velocity = (self.pos - start) / delta_time;
+        self.pos != start
     }
 
-    pub fn input_update(&mut self, camera: &mut Camera2D, state: &mut State, world: &World) {
+    pub fn input_update(&mut self, camera: &mut Camera2D, state: &mut State, world: &mut World) {
+        let (tile_x, tile_y) = world.tile_at(self.pos);
+        world.load_chunks_around(tile_x, tile_y, 1);
+
+        // The ground under the player decides how fast they can walk
+        let tile_size = world.tile_size as f32;
+        let speed = world
+            .get_tile(tile_x, tile_y)
+            .map_or(1.0, |block| world.tiles.get(block).walk_speed)
+            * WALK_SPEED
+            * tile_size;
+        let delta_time = state.delta_time().min(MAX_DELTA);
+
+        // Rays are recast once at the end if anything moved
+        let mut moved = false;
+        let mut wish = Vector2::zero();
+
         unsafe {
-            if ffi::IsKeyDown(ffi::KeyboardKey::KEY_W as c_int) {
-                self.pos.x += self.direction.x * self.velocity;
-                self.pos.y += self.direction.y * self.velocity;
-                self.distances = my_ray::cast_fov(self.pos, self.angle, NUM_RAYS, world);
+            // Turning comes first so the player walks the way they now face.
+            // The arrow keys turn with either control scheme, A and D only
+            // turn with tank controls and strafe otherwise.
+            let mut turn = 0.0;
+            if ffi::IsKeyDown(ffi::KeyboardKey::KEY_LEFT as c_int) {
+                turn -= TURN_SPEED * delta_time;
+            }
+            if ffi::IsKeyDown(ffi::KeyboardKey::KEY_RIGHT as c_int) {
+                turn += TURN_SPEED * delta_time;
+            }
+            if self.controls == Controls::Tank {
+                if ffi::IsKeyDown(ffi::KeyboardKey::KEY_A as c_int) {
+                    turn -= TURN_SPEED * delta_time;
+                }
+                if ffi::IsKeyDown(ffi::KeyboardKey::KEY_D as c_int) {
+                    turn += TURN_SPEED * delta_time;
+                }
             }
-            if ffi::IsKeyDown(ffi::KeyboardKey::KEY_A as c_int) {
-                self.angle -= 10.0;
-                self.distances = my_ray::cast_fov(self.pos, self.angle, NUM_RAYS, world);
+            if self.skip_mouse_delta {
+                self.skip_mouse_delta = false;
+            } else if self.cursor_captured {
+                turn += ffi::GetMouseDelta().x * self.mouse_sensitivity;
+            }
+            if turn != 0.0 {
+                self.angle = (self.angle + turn).rem_euclid(360.0);
+                moved = true;
+            }
+            self.direction.x = self.angle.to_radians().cos();
+            self.direction.y = self.angle.to_radians().sin();
+
+            // Right of the way the player faces, y points down the screen
+            let right = Vector2::new(-self.direction.y, self.direction.x);
+            if ffi::IsKeyDown(ffi::KeyboardKey::KEY_W as c_int) {
+                wish += self.direction;
             }
             if ffi::IsKeyDown(ffi::KeyboardKey::KEY_S as c_int) {
-                self.pos.x -= self.direction.x * self.velocity;
-                self.pos.y -= self.direction.y * self.velocity;
-                self.distances = my_ray::cast_fov(self.pos, self.angle, NUM_RAYS, world);
+                wish -= self.direction;
+            }
+            if self.controls == Controls::Strafe {
+                if ffi::IsKeyDown(ffi::KeyboardKey::KEY_A as c_int) {
+                    wish -= right;
+                }
+                if ffi::IsKeyDown(ffi::KeyboardKey::KEY_D as c_int) {
+                    wish += right;
+                }
+            }
+            // Walking diagonally isn't any faster
+            if wish.length() > 1.0 {
+                wish = wish.normalized();
             }
-            if ffi::IsKeyDown(ffi::KeyboardKey::KEY_D as c_int) {
-                self.angle += 10.0;
-                self.distances = my_ray::cast_fov(self.pos, self.angle, NUM_RAYS, world);
+            if self.walk(wish * speed, delta_time, world) {
+                moved = true;
             }
-            if self.angle >= 360.0 {
-                self.angle -= 360.0;
+            if moved {
+                self.cast_rays(world);
+                self.look_around(world);
             }
-            if self.angle < 0.0 {
-                self.angle += 360.0;
+            self.target = self.look_at(world);
+            if self.interact(world) {
+                self.cast_rays(world);
+                self.look_around(world);
+                self.target = self.look_at(world);
             }
 
             // Camera zoom adjustments
@@ -134,14 +624,45 @@ impl Player {
             if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_O as i32) {
                 state.change_view(View::FPS);
             }
+            // Skips half a day ahead
+            if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_N as i32) {
+                state.ticks += state.day.day_length / 2;
+                println!(
+                    "Time of day: {:.2}, daylight: {:.2}",
+                    state.day.time_of_day(state.ticks),
+                    state.daylight()
+                );
+            }
             if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_G as i32) {
                 let _ = world::World::from_file("data.cade");
             }
 
+            // F1 switches between tank and strafe controls, F2 turns mouse-look
+            // on and off and [ and ] change how fast the mouse turns
+            if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_F1 as i32) {
+                self.controls = match self.controls {
+                    Controls::Tank => Controls::Strafe,
+                    Controls::Strafe => Controls::Tank,
+                };
+                println!("Controls: {:?}", self.controls);
+            }
+            if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_F2 as i32) {
+                self.mouse_look = !self.mouse_look;
+                println!("Mouse-look: {}", self.mouse_look);
+            }
+            if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_LEFT_BRACKET as i32) {
+                self.set_mouse_sensitivity(self.mouse_sensitivity - 0.01);
+                println!("Mouse sensitivity: {:.2}", self.mouse_sensitivity);
+            }
+            if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_RIGHT_BRACKET as i32) {
+                self.set_mouse_sensitivity(self.mouse_sensitivity + 0.01);
+                println!("Mouse sensitivity: {:.2}", self.mouse_sensitivity);
+            }
+            // The editor needs the cursor back
+            self.capture_cursor(self.mouse_look && state.view != View::Editor);
+
             camera.target.x = self.pos.x;
             camera.target.y = self.pos.y;
-            self.direction.x = self.angle.to_radians().cos();
-            self.direction.y = self.angle.to_radians().sin();
 
             // Display the player's angle on the screen
             let angle_text = format!("Angle: {:.2}", self.angle);
@@ -182,3 +703,72 @@ impl Player {
         );
     }
 }
+
+// Moves from one velocity towards another by no more than max_change
+fn approach(from: Vector2, to: Vector2, max_change: f32) -> Vector2 {
+    let change = to - from;
+    let length = change.length();
+    if length <= max_change {
+        to
+    } else {
+        from + change * (max_change / length)
+    }
+}
+
+// The open tile a wall face looks out onto, which decides how lit the face is
+fn in_front_of(hit: &my_ray::RayHit) -> (i32, i32) {
+    match hit.face {
+        my_ray::Face::West => (hit.tile_x - 1, hit.tile_y),
+        my_ray::Face::East => (hit.tile_x + 1, hit.tile_y),
+        my_ray::Face::North => (hit.tile_x, hit.tile_y - 1),
+        my_ray::Face::South => (hit.tile_x, hit.tile_y + 1),
+    }
+}
+
+// The one pixel wide column of the atlas a ray's wall slice is drawn from, u
+// runs 0..1 across the face
+fn wall_column(block: Blocks, u: f32, world: &World) -> Rectangle {
+    let section: Rectangle = world.tiles.get(block).atlas.into();
+    Rectangle {
+        x: section.x + (u * section.width).floor().min(section.width - 1.0),
+        y: section.y,
+        width: 1.0,
+        height: section.height,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entity::{Direction, EntityKind};
+    use crate::terrain::TerrainSettings;
+
+    fn spawn_tile(world: &mut World) -> (i32, i32) {
+        let pos = spawn_point(world);
+        world.tile_at(pos)
+    }
+
+    #[test]
+    fn spawns_on_the_nearest_open_tile() {
+        let mut world = World::new(8, 8);
+        assert_eq!(spawn_tile(&mut world), (0, 0));
+
+        for y in 0..8 {
+            for x in 0..8 {
+                if (x, y) != (5, 6) && (x, y) != (3, 7) {
+                    world.set_tile(x, y, Blocks::STONE);
+                }
+            }
+        }
+        assert_eq!(spawn_tile(&mut world), (3, 7));
+        world.set_tile(0, 0, Blocks::GRASS);
+        world
+            .place_entity(EntityKind::Chest, 0, 0, Direction::North)
+            .unwrap();
+        assert_eq!(spawn_tile(&mut world), (3, 7));
+
+        let mut world = World::generate(3, TerrainSettings::default());
+        let (x, y) = spawn_tile(&mut world);
+        assert!(!world.is_solid(x, y));
+    }
+}
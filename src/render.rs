@@ -1,3 +1,4 @@
+use crate::fps::FloorCaster;
 use crate::Player;
 use crate::Selector;
 use crate::State;
@@ -16,6 +17,8 @@ pub enum RendererType {
 pub struct Renderer {
     pub render_t: RendererType,
     pub camera: Camera2D,
+    // Floor and sky for the FPS view, which only has walls without it
+    pub floor: Option<FloorCaster>,
 }
 
 impl Renderer {
@@ -28,6 +31,7 @@ impl Renderer {
                 rotation: 0.0,
                 zoom: 0.0,
             },
+            floor: None,
         }
     }
 
@@ -42,14 +46,20 @@ impl Renderer {
     ) {
         match self.render_t {
             RendererType::Editor => {
-                REditor::render(d, texture_atlas, world, &mut self.camera, &selector)
+                REditor::render(state, d, texture_atlas, world, &mut self.camera, &selector)
             }
             RendererType::Minimap => {
                 RMinimap::render(state, d, texture_atlas, world, player, &mut self.camera)
             }
-            RendererType::FPS => {
-                RFPS::render(state, d, texture_atlas, world, player, &mut self.camera)
-            }
+            RendererType::FPS => RFPS::render(
+                state,
+                d,
+                texture_atlas,
+                self.floor.as_mut(),
+                world,
+                player,
+                &mut self.camera,
+            ),
         }
     }
 }
@@ -69,8 +79,14 @@ impl RMinimap {
         player: &Player,
         camera: &mut Camera2D,
     ) {
-        world.render(d, texture_atlas, camera);
-        player.render(state, d, camera, world);
+        world.render(
+            d,
+            texture_atlas,
+            camera,
+            Some(&player.vision),
+            Some(&state.light),
+        );
+        player.render(state, d, texture_atlas, None, camera, world);
         camera.target = Vector2::new(player.pos.x as f32, player.pos.y as f32);
         camera.offset = Vector2::new(
             unsafe { raylib::ffi::GetScreenWidth() as f32 / 2.0 },
@@ -85,6 +101,7 @@ pub struct REditor {}
 
 impl REditor {
     fn render(
+        state: &State,
         d: &mut RaylibDrawHandle,
         texture_atlas: &Texture2D,
         world: &mut World,
@@ -92,7 +109,7 @@ impl REditor {
         selector: &Selector,
     ) {
         d.draw_circle(200, 200, 20.0, Color::BLUE);
-        world.render(d, texture_atlas, camera);
+        world.render(d, texture_atlas, camera, None, Some(&state.light));
         selector.render(d, texture_atlas, world, camera);
     }
 }
@@ -106,11 +123,12 @@ impl RFPS {
         state: &State,
         d: &mut RaylibDrawHandle,
         texture_atlas: &Texture2D,
+        floor: Option<&mut FloorCaster>,
         world: &mut World,
         player: &Player,
         camera: &mut Camera2D,
     ) {
-        player.render(state, d, camera, world);
+        player.render(state, d, texture_atlas, floor, camera, world);
         // d.draw_circle(200, 200, 20.0, Color::RED);
     }
 }
@@ -0,0 +1,55 @@
+use raylib::prelude::*;
+
+// Minable ore that sits on top of the terrain. Like Blocks, the discriminants
+// are written to save files.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Resource {
+    Iron = 1,
+    Copper = 2,
+    Coal = 3,
+    Stone = 4,
+}
+
+impl Resource {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Resource::Iron),
+            2 => Some(Resource::Copper),
+            3 => Some(Resource::Coal),
+            4 => Some(Resource::Stone),
+            _ => None,
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        self as u8
+    }
+
+    // The ore overlays live on the fourth row of the atlas
+    pub fn texture_section(self) -> Rectangle {
+        let x = match self {
+            Resource::Iron => 0.0,
+            Resource::Copper => 32.0,
+            Resource::Coal => 64.0,
+            Resource::Stone => 96.0,
+        };
+        Rectangle {
+            x,
+            y: 96.0,
+            width: 32.0,
+            height: 32.0,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ResourceTile {
+    pub kind: Resource,
+    pub amount: u32,
+}
+
+impl ResourceTile {
+    pub fn new(kind: Resource, amount: u32) -> Self {
+        ResourceTile { kind, amount }
+    }
+}
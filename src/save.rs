@@ -0,0 +1,969 @@
+use crate::chunk::{Chunk, ChunkPos, CHUNK_SIZE};
+use crate::entity::{Direction, Entity, EntityId, EntityKind, PlacementError};
+use crate::player::PlayerState;
+use crate::resource::{Resource, ResourceTile};
+use crate::terrain::{Generator, TerrainSettings};
+use crate::world::{Blocks, World};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+// Layout of a .cade file, all numbers little endian:
+//
+//   magic      4 bytes  "CADE"
+//   version    u16
+//   width      u32      in tiles, at most MAX_SIZE
+//   height     u32      in tiles, at most MAX_SIZE
+//   origin_x   i32      tile coordinates of the top left corner
+//   origin_y   i32
+//   tile_size  u32      in pixels
+//   flags      u8       bit 0 set if the world is bounded to width x height,
+//                       bit 1 if unvisited chunks come from the generator
+//   generator  u64 seed, then the TerrainSettings fields in order as f32,
+//              except octaves and ore_richness which are u32. All zero
+//              when flag bit 1 isn't set.
+//   checksum   u32      CRC-32 of the header up to here followed by
+//                       everything after it
+//   chunks     u32 count, then per chunk: x i32, y i32, run count u32, and
+//              that many runs of (length u16, block u8) covering its tiles
+//              row by row
+//   objects    u32 length, then that many bytes of zlib data holding:
+//     resources  u32 count, then per tile: x i32, y i32, kind u8, amount u32
+//     entities   u32 count, then per building: id u32, kind u8, x i32, y i32, direction u8
+//     explored   u32 count, then per chunk the player has seen part of: x i32,
+//                y i32, run count u32, and that many u16 lengths of tiles
+//                alternating unexplored and explored, row by row, starting
+//                with unexplored
+//     player     u8 1 if the player was saved, then x f32, y f32, angle f32
+//                and the game clock as u64 ticks
+//
+// Up to version 8 the checksum only covered what comes after the header.
+// Up to version 7 the player wasn't saved, so they started at the default
+// position at sunrise.
+// Up to version 6 there was no generator, so generated worlds filled in
+// unvisited chunks with grass once loaded.
+// Up to version 5 nothing was explored.
+// Up to version 4 the tiles were stored as width * height bytes row by row,
+// followed by the resources and entities uncompressed.
+// Version 1 had no resource section, version 2 had no entity section and
+// version 3 had no flags, those worlds are all unbounded.
+// Files written before the header existed start straight with the world size
+// and are upgraded when they're loaded.
+
+pub const MAGIC: [u8; 4] = *b"CADE";
+pub const VERSION: u16 = 9;
+const HEADER_LEN: usize = 4 + 2 + 4 + 4 + 4 + 4 + 4 + 1 + GENERATOR_LEN + 4;
+pub const GENERATOR_LEN: usize = 8 + 10 * 4;
+// Bounded worlds are allocated in full when they're loaded, so a broken
+// header mustn't be able to ask for more than this many tiles along a side
+pub const MAX_SIZE: usize = 8192;
+
+const FLAG_BOUNDED: u8 = 1;
+const FLAG_GENERATED: u8 = 2;
+
+#[derive(Debug)]
+pub enum SaveError {
+    Io(io::Error),
+    Database(rusqlite::Error),
+    BadMagic,
+    // Wide enough for any database schema version as well
+    UnsupportedVersion(i64),
+    // `part` names what was cut short, e.g. "header"
+    Truncated {
+        part: &'static str,
+        expected: usize,
+        found: usize,
+    },
+    TooLarge {
+        width: usize,
+        height: usize,
+    },
+    ChecksumMismatch {
+        expected: u32,
+        found: u32,
+    },
+    InvalidBlock {
+        index: usize,
+        value: u8,
+    },
+    // The runs of a chunk don't add up to a whole chunk
+    InvalidChunk {
+        x: i32,
+        y: i32,
+    },
+    InvalidResource {
+        value: u8,
+    },
+    InvalidEntity {
+        value: u8,
+    },
+    Overlap(PlacementError),
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveError::Io(e) => write!(f, "io error: {}", e),
+            SaveError::Database(e) => write!(f, "database error: {}", e),
+            SaveError::BadMagic => write!(f, "not a .cade world file"),
+            SaveError::UnsupportedVersion(version) => {
+                write!(f, "unsupported world file version {}", version)
+            }
+            SaveError::Truncated {
+                part,
+                expected,
+                found,
+            } => write!(f, "{} is {} bytes but needs {}", part, found, expected),
+            SaveError::TooLarge { width, height } => write!(
+                f,
+                "world is {}x{} tiles, at most {}x{} can be loaded",
+                width, height, MAX_SIZE, MAX_SIZE
+            ),
+            SaveError::ChecksumMismatch { expected, found } => write!(
+                f,
+                "checksum mismatch (expected {:08x}, found {:08x})",
+                expected, found
+            ),
+            SaveError::InvalidBlock { index, value } => {
+                write!(f, "invalid block type {} at tile {}", value, index)
+            }
+            SaveError::InvalidChunk { x, y } => {
+                write!(
+                    f,
+                    "chunk ({}, {}) doesn't have the right number of tiles",
+                    x, y
+                )
+            }
+            SaveError::InvalidResource { value } => write!(f, "invalid resource type {}", value),
+            SaveError::InvalidEntity { value } => write!(f, "invalid entity field {}", value),
+            SaveError::Overlap(e) => write!(f, "overlapping entities: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SaveError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SaveError::Io(e) => Some(e),
+            SaveError::Database(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<PlacementError> for SaveError {
+    fn from(e: PlacementError) -> Self {
+        SaveError::Overlap(e)
+    }
+}
+
+impl From<io::Error> for SaveError {
+    fn from(e: io::Error) -> Self {
+        SaveError::Io(e)
+    }
+}
+
+impl From<rusqlite::Error> for SaveError {
+    fn from(e: rusqlite::Error) -> Self {
+        SaveError::Database(e)
+    }
+}
+
+// Which layout a file was read from
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Format {
+    Legacy,
+    Versioned(u16),
+}
+
+pub fn encode(world: &World, player: Option<PlayerState>) -> Vec<u8> {
+    let (origin_x, origin_y, width, height) = world.bounds().unwrap_or((0, 0, 0, 0));
+
+    // Sorted so the same world always gives the same bytes
+    let mut chunks: Vec<(&ChunkPos, &Chunk)> = world.chunks().collect();
+    chunks.sort_by_key(|(pos, _)| (pos.y, pos.x));
+
+    let mut payload = Vec::new();
+    let mut resources = Vec::new();
+    let mut explored = Vec::new();
+    payload.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
+    for (pos, chunk) in chunks {
+        let runs = encode_runs(chunk);
+        payload.extend_from_slice(&pos.x.to_le_bytes());
+        payload.extend_from_slice(&pos.y.to_le_bytes());
+        payload.extend_from_slice(&(runs.len() as u32).to_le_bytes());
+        for (length, block) in runs {
+            payload.extend_from_slice(&length.to_le_bytes());
+            payload.push(block.to_u8());
+        }
+        // A single run means nothing in the chunk has been seen
+        let runs = explored_runs(chunk);
+        if runs.len() > 1 {
+            explored.push((pos, runs));
+        }
+
+        let (chunk_x, chunk_y) = pos.origin();
+        for j in 0..CHUNK_SIZE {
+            for i in 0..CHUNK_SIZE {
+                if let Some(resource) = chunk.get_resource(i, j) {
+                    resources.push((chunk_x + i as i32, chunk_y + j as i32, resource));
+                }
+            }
+        }
+    }
+
+    let mut objects = Vec::new();
+    objects.extend_from_slice(&(resources.len() as u32).to_le_bytes());
+    for (x, y, resource) in resources {
+        objects.extend_from_slice(&x.to_le_bytes());
+        objects.extend_from_slice(&y.to_le_bytes());
+        objects.push(resource.kind.to_u8());
+        objects.extend_from_slice(&resource.amount.to_le_bytes());
+    }
+
+    let mut entities: Vec<&Entity> = world.entities.iter().collect();
+    entities.sort_by_key(|entity| entity.id);
+    objects.extend_from_slice(&(entities.len() as u32).to_le_bytes());
+    for entity in entities {
+        objects.extend_from_slice(&entity.id.0.to_le_bytes());
+        objects.push(entity.kind.to_u8());
+        objects.extend_from_slice(&entity.x.to_le_bytes());
+        objects.extend_from_slice(&entity.y.to_le_bytes());
+        objects.push(entity.direction.to_u8());
+    }
+
+    objects.extend_from_slice(&(explored.len() as u32).to_le_bytes());
+    for (pos, runs) in explored {
+        objects.extend_from_slice(&pos.x.to_le_bytes());
+        objects.extend_from_slice(&pos.y.to_le_bytes());
+        objects.extend_from_slice(&(runs.len() as u32).to_le_bytes());
+        for length in runs {
+            objects.extend_from_slice(&length.to_le_bytes());
+        }
+    }
+
+    match player {
+        Some(player) => {
+            objects.push(1);
+            objects.extend_from_slice(&player.x.to_le_bytes());
+            objects.extend_from_slice(&player.y.to_le_bytes());
+            objects.extend_from_slice(&player.angle.to_le_bytes());
+            objects.extend_from_slice(&player.ticks.to_le_bytes());
+        }
+        None => objects.push(0),
+    }
+
+    // Writing into a Vec can't fail
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&objects).unwrap();
+    let objects = encoder.finish().unwrap();
+    payload.extend_from_slice(&(objects.len() as u32).to_le_bytes());
+    payload.extend_from_slice(&objects);
+
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend_from_slice(&MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(width as u32).to_le_bytes());
+    bytes.extend_from_slice(&(height as u32).to_le_bytes());
+    bytes.extend_from_slice(&origin_x.to_le_bytes());
+    bytes.extend_from_slice(&origin_y.to_le_bytes());
+    bytes.extend_from_slice(&(world.tile_size as u32).to_le_bytes());
+    let mut flags = 0;
+    if world.size().is_some() {
+        flags |= FLAG_BOUNDED;
+    }
+    if world.generator().is_some() {
+        flags |= FLAG_GENERATED;
+    }
+    bytes.push(flags);
+    match world.generator() {
+        Some(generator) => bytes.extend_from_slice(&encode_generator(&generator)),
+        None => bytes.extend_from_slice(&[0; GENERATOR_LEN]),
+    }
+    let checksum = crc32_parts(&[&bytes, &payload]);
+    bytes.extend_from_slice(&checksum.to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    bytes
+}
+
+// The player is None if they weren't saved
+pub fn decode(bytes: &[u8]) -> Result<(World, Option<PlayerState>, Format), SaveError> {
+    if bytes.len() >= MAGIC.len() && bytes[..MAGIC.len()] == MAGIC {
+        decode_versioned(bytes)
+    } else {
+        // Legacy files have no magic and are only recognised by their size,
+        // so anything else that doesn't fit isn't a world file at all
+        match decode_legacy(bytes) {
+            Ok(world) => Ok((world, None, Format::Legacy)),
+            Err(SaveError::Truncated { .. }) => Err(SaveError::BadMagic),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn decode_versioned(bytes: &[u8]) -> Result<(World, Option<PlayerState>, Format), SaveError> {
+    let mut reader = Reader::new(bytes, "header");
+    if reader.take(4)? != MAGIC {
+        return Err(SaveError::BadMagic);
+    }
+
+    let version = reader.u16()?;
+    if version == 0 || version > VERSION {
+        return Err(SaveError::UnsupportedVersion(version.into()));
+    }
+
+    let width = reader.u32()? as usize;
+    let height = reader.u32()? as usize;
+    let origin_x = reader.i32()?;
+    let origin_y = reader.i32()?;
+    let tile_size = reader.u32()? as usize;
+    let flags = if version >= 4 {
+        reader.array::<1>()?[0]
+    } else {
+        0
+    };
+    let generator = if version >= 7 {
+        Some(decode_generator(reader.take(GENERATOR_LEN)?)?)
+    } else {
+        None
+    };
+    let header = &bytes[..reader.pos];
+    let checksum = reader.u32()?;
+    let payload = reader.take(reader.remaining())?;
+
+    let found = if version >= 9 {
+        crc32_parts(&[header, payload])
+    } else {
+        crc32(payload)
+    };
+    if found != checksum {
+        return Err(SaveError::ChecksumMismatch {
+            expected: checksum,
+            found,
+        });
+    }
+    // Older headers weren't checked, and a broken one could still ask for a
+    // world too big to allocate
+    if (flags & FLAG_BOUNDED != 0 || version < 5) && (width > MAX_SIZE || height > MAX_SIZE) {
+        return Err(SaveError::TooLarge { width, height });
+    }
+
+    let mut reader = Reader::new(payload, "payload");
+    let mut world = if flags & FLAG_BOUNDED != 0 {
+        World::new(width, height)
+    } else {
+        World::empty(tile_size)
+    };
+    world.tile_size = tile_size;
+    if flags & FLAG_GENERATED != 0 {
+        world.set_generator(generator);
+    }
+
+    if version >= 5 {
+        read_chunks(&mut reader, &mut world)?;
+        let length = reader.u32()? as usize;
+        let mut objects = Vec::new();
+        ZlibDecoder::new(reader.take(length)?).read_to_end(&mut objects)?;
+        let mut reader = Reader::new(&objects, "objects");
+        read_objects(&mut reader, version, &mut world)?;
+        let player = if version >= 8 {
+            read_player(&mut reader)?
+        } else {
+            None
+        };
+        Ok((world, player, Format::Versioned(version)))
+    } else {
+        let grid = reader.take(width * height)?;
+        read_grid(grid, width, origin_x, origin_y, &mut world)?;
+        read_objects(&mut reader, version, &mut world)?;
+        Ok((world, None, Format::Versioned(version)))
+    }
+}
+
+// The seed and settings as they're laid out in the header
+pub fn encode_generator(generator: &Generator) -> [u8; GENERATOR_LEN] {
+    let s = &generator.settings;
+    let mut bytes = Vec::with_capacity(GENERATOR_LEN);
+    bytes.extend_from_slice(&generator.seed.to_le_bytes());
+    bytes.extend_from_slice(&s.scale.to_le_bytes());
+    bytes.extend_from_slice(&s.octaves.to_le_bytes());
+    bytes.extend_from_slice(&s.persistence.to_le_bytes());
+    bytes.extend_from_slice(&s.lacunarity.to_le_bytes());
+    bytes.extend_from_slice(&s.water_level.to_le_bytes());
+    bytes.extend_from_slice(&s.sand_level.to_le_bytes());
+    bytes.extend_from_slice(&s.stone_level.to_le_bytes());
+    bytes.extend_from_slice(&s.ore_scale.to_le_bytes());
+    bytes.extend_from_slice(&s.ore_threshold.to_le_bytes());
+    bytes.extend_from_slice(&s.ore_richness.to_le_bytes());
+    bytes.try_into().unwrap()
+}
+
+pub fn decode_generator(bytes: &[u8]) -> Result<Generator, SaveError> {
+    let mut reader = Reader::new(bytes, "generator");
+    let seed = reader.u64()?;
+    let settings = TerrainSettings {
+        scale: reader.f32()?,
+        octaves: reader.u32()?,
+        persistence: reader.f32()?,
+        lacunarity: reader.f32()?,
+        water_level: reader.f32()?,
+        sand_level: reader.f32()?,
+        stone_level: reader.f32()?,
+        ore_scale: reader.f32()?,
+        ore_threshold: reader.f32()?,
+        ore_richness: reader.u32()?,
+    };
+    Ok(Generator::new(seed, settings))
+}
+
+// Splits a chunk's tiles, row by row, into runs of the same block
+fn encode_runs(chunk: &Chunk) -> Vec<(u16, Blocks)> {
+    let mut runs: Vec<(u16, Blocks)> = Vec::new();
+    for j in 0..CHUNK_SIZE {
+        for i in 0..CHUNK_SIZE {
+            let block = chunk.get(i, j);
+            match runs.last_mut() {
+                Some((length, last)) if *last == block => *length += 1,
+                _ => runs.push((1, block)),
+            }
+        }
+    }
+    runs
+}
+
+// Lengths of the alternating unexplored and explored stretches of a chunk's
+// tiles, row by row
+fn explored_runs(chunk: &Chunk) -> Vec<u16> {
+    let mut runs = vec![0];
+    let mut explored = false;
+    for j in 0..CHUNK_SIZE {
+        for i in 0..CHUNK_SIZE {
+            if chunk.is_explored(i, j) != explored {
+                explored = !explored;
+                runs.push(0);
+            }
+            *runs.last_mut().unwrap() += 1;
+        }
+    }
+    runs
+}
+
+fn read_chunks(reader: &mut Reader, world: &mut World) -> Result<(), SaveError> {
+    let count = reader.u32()?;
+    for _ in 0..count {
+        let pos = ChunkPos::new(reader.i32()?, reader.i32()?);
+        let invalid = SaveError::InvalidChunk { x: pos.x, y: pos.y };
+
+        let mut chunk = Chunk::new(Blocks::GRASS);
+        let mut index = 0;
+        for _ in 0..reader.u32()? {
+            let length = reader.u16()? as usize;
+            let value = reader.array::<1>()?[0];
+            let block = world
+                .tiles
+                .block(value)
+                .ok_or(SaveError::InvalidBlock { index, value })?;
+            if index + length > CHUNK_SIZE * CHUNK_SIZE {
+                return Err(invalid);
+            }
+            for index in index..index + length {
+                chunk.set(index % CHUNK_SIZE, index / CHUNK_SIZE, block);
+            }
+            index += length;
+        }
+
+        if index != CHUNK_SIZE * CHUNK_SIZE {
+            return Err(invalid);
+        }
+        world.insert_chunk(pos, chunk);
+    }
+    Ok(())
+}
+
+// Tiles as stored up to version 4
+fn read_grid(
+    grid: &[u8],
+    width: usize,
+    origin_x: i32,
+    origin_y: i32,
+    world: &mut World,
+) -> Result<(), SaveError> {
+    for (index, &value) in grid.iter().enumerate() {
+        let x = origin_x + (index % width) as i32;
+        let y = origin_y + (index / width) as i32;
+
+        let block = world
+            .tiles
+            .block(upgrade_block_id(value))
+            .ok_or(SaveError::InvalidBlock { index, value })?;
+        world.set_tile(x, y, block);
+    }
+    Ok(())
+}
+
+// The resource, entity and explored sections
+fn read_objects(reader: &mut Reader, version: u16, world: &mut World) -> Result<(), SaveError> {
+    if version >= 2 {
+        let count = reader.u32()?;
+        for _ in 0..count {
+            let x = reader.i32()?;
+            let y = reader.i32()?;
+            let value = reader.array::<1>()?[0];
+            let amount = reader.u32()?;
+            let kind = Resource::from_u8(value).ok_or(SaveError::InvalidResource { value })?;
+            world.set_resource(x, y, Some(ResourceTile::new(kind, amount)));
+        }
+    }
+
+    if version >= 3 {
+        let count = reader.u32()?;
+        for _ in 0..count {
+            let id = EntityId(reader.u32()?);
+            let value = reader.array::<1>()?[0];
+            let kind = EntityKind::from_u8(value).ok_or(SaveError::InvalidEntity { value })?;
+            let x = reader.i32()?;
+            let y = reader.i32()?;
+            let value = reader.array::<1>()?[0];
+            let direction = Direction::from_u8(value).ok_or(SaveError::InvalidEntity { value })?;
+            world.entities.insert(Entity {
+                id,
+                kind,
+                x,
+                y,
+                direction,
+            })?;
+        }
+    }
+
+    if version >= 6 {
+        let count = reader.u32()?;
+        for _ in 0..count {
+            let pos = ChunkPos::new(reader.i32()?, reader.i32()?);
+            let invalid = SaveError::InvalidChunk { x: pos.x, y: pos.y };
+            if world.chunk(pos).is_none() {
+                return Err(invalid);
+            }
+
+            let (origin_x, origin_y) = pos.origin();
+            let mut index = 0;
+            for run in 0..reader.u32()? {
+                let length = reader.u16()? as usize;
+                if index + length > CHUNK_SIZE * CHUNK_SIZE {
+                    return Err(invalid);
+                }
+                if run % 2 == 1 {
+                    for index in index..index + length {
+                        let x = origin_x + (index % CHUNK_SIZE) as i32;
+                        let y = origin_y + (index / CHUNK_SIZE) as i32;
+                        world.explore(x, y);
+                    }
+                }
+                index += length;
+            }
+            if index != CHUNK_SIZE * CHUNK_SIZE {
+                return Err(invalid);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_player(reader: &mut Reader) -> Result<Option<PlayerState>, SaveError> {
+    if reader.array::<1>()?[0] == 0 {
+        return Ok(None);
+    }
+    Ok(Some(PlayerState {
+        x: reader.f32()?,
+        y: reader.f32()?,
+        angle: reader.f32()?,
+        ticks: reader.u64()?,
+    }))
+}
+
+// The unversioned layout: a u32 world size, the tile size, then size * size
+// tiles stored column by column. The tile size was documented as 4 bytes but
+// written as an 8 byte usize, so both widths are accepted here.
+fn decode_legacy(bytes: &[u8]) -> Result<World, SaveError> {
+    let mut reader = Reader::new(bytes, "legacy world file");
+    let size = reader.u32()? as usize;
+    let tile_count = size * size;
+
+    let tile_size = match reader.remaining() {
+        n if n == tile_count + 8 => u64::from_le_bytes(reader.array()?) as usize,
+        n if n == tile_count + 4 => u32::from_le_bytes(reader.array()?) as usize,
+        n => {
+            return Err(SaveError::Truncated {
+                part: "legacy world file",
+                expected: 4 + 8 + tile_count,
+                found: 4 + n,
+            })
+        }
+    };
+
+    // These worlds never grew past the size they were made with
+    let mut world = World::new(size, size);
+    world.tile_size = tile_size;
+    for (index, &value) in reader.take(tile_count)?.iter().enumerate() {
+        let block = world
+            .tiles
+            .block(upgrade_block_id(value))
+            .ok_or(SaveError::InvalidBlock { index, value })?;
+        world.set_tile((index / size) as i32, (index % size) as i32, block);
+    }
+
+    Ok(world)
+}
+
+// Block id 2 was the player before it moved out of the terrain
+fn upgrade_block_id(value: u8) -> u8 {
+    if value == 2 {
+        Blocks::GRASS.to_u8()
+    } else {
+        value
+    }
+}
+
+// Rewrites a legacy file in the current format, returns whether anything changed
+pub fn upgrade_file(file_name: &str) -> Result<bool, SaveError> {
+    let (world, player, format) = decode(&std::fs::read(file_name)?)?;
+    if format == Format::Versioned(VERSION) {
+        return Ok(false);
+    }
+
+    write_atomic(file_name, &encode(&world, player))?;
+    println!(
+        "Upgraded {} from {:?} to version {}",
+        file_name, format, VERSION
+    );
+    Ok(true)
+}
+
+// Writes next to the target first and then renames over it, so a crash
+// halfway through leaves the old file untouched
+pub fn write_atomic(file_name: &str, bytes: &[u8]) -> io::Result<()> {
+    let temp_name = format!("{}.tmp", file_name);
+    let mut file = std::fs::File::create(&temp_name)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&temp_name, file_name)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    // Which part of the file this reads, for Truncated errors
+    part: &'static str,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8], part: &'static str) -> Self {
+        Reader {
+            bytes,
+            pos: 0,
+            part,
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        self.bytes.len() - self.pos
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SaveError> {
+        if self.remaining() < len {
+            return Err(SaveError::Truncated {
+                part: self.part,
+                expected: self.pos + len,
+                found: self.bytes.len(),
+            });
+        }
+        let slice = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], SaveError> {
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    fn u16(&mut self) -> Result<u16, SaveError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, SaveError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn i32(&mut self) -> Result<i32, SaveError> {
+        Ok(i32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, SaveError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn f32(&mut self) -> Result<f32, SaveError> {
+        Ok(f32::from_le_bytes(self.array()?))
+    }
+}
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                0xEDB8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+// Standard CRC-32 (the one zip and png use)
+pub fn crc32(bytes: &[u8]) -> u32 {
+    crc32_parts(&[bytes])
+}
+
+// The CRC-32 of the parts one after the other
+fn crc32_parts(parts: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in parts.iter().flat_map(|part| part.iter()) {
+        crc = CRC_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terrain::TerrainSettings;
+
+    const PLAYER: PlayerState = PlayerState {
+        x: 40.0,
+        y: 72.5,
+        angle: 135.0,
+        ticks: 987,
+    };
+
+    // A bounded world with a bit of everything that gets saved
+    fn sample_world() -> World {
+        let mut world = World::new(40, 20);
+        world.tile_size = 32;
+        for x in 0..40 {
+            world.set_tile(x, x % 20, Blocks::STONE);
+        }
+        world.set_tile(35, 3, Blocks::WATER);
+        world.set_tile(36, 3, Blocks::SAND);
+        world.set_resource(2, 5, Some(ResourceTile::new(Resource::Iron, 250)));
+        world.set_resource(3, 5, Some(ResourceTile::new(Resource::Copper, 1)));
+        world
+            .place_entity(EntityKind::Assembler, 14, 2, Direction::East)
+            .unwrap();
+        world
+            .place_entity(EntityKind::Lamp, 38, 1, Direction::North)
+            .unwrap();
+        for x in 0..12 {
+            world.explore(x, 7);
+        }
+        world
+    }
+
+    // A file from before version 7, when the header had no generator
+    fn old_file(version: u16, width: u32, height: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&version.to_le_bytes());
+        bytes.extend_from_slice(&width.to_le_bytes());
+        bytes.extend_from_slice(&height.to_le_bytes());
+        bytes.extend_from_slice(&0i32.to_le_bytes());
+        bytes.extend_from_slice(&0i32.to_le_bytes());
+        bytes.extend_from_slice(&32u32.to_le_bytes());
+        if version >= 4 {
+            bytes.push(flags);
+        }
+        bytes.extend_from_slice(&crc32(payload).to_le_bytes());
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    // Rewrites the checksum after the file has been tampered with
+    fn fix_checksum(bytes: &mut [u8]) {
+        let crc = crc32_parts(&[&bytes[..HEADER_LEN - 4], &bytes[HEADER_LEN..]]);
+        bytes[HEADER_LEN - 4..HEADER_LEN].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    #[test]
+    fn round_trip() {
+        let world = sample_world();
+        for player in [None, Some(PLAYER)] {
+            let bytes = encode(&world, player);
+            let (loaded, loaded_player, format) = decode(&bytes).unwrap();
+            assert_eq!(format, Format::Versioned(VERSION));
+            assert_eq!(loaded_player, player);
+            assert_eq!(loaded.bounds(), world.bounds());
+            assert_eq!(loaded.tile_size, 32);
+            assert_eq!(loaded.get_tile(35, 3), Some(Blocks::WATER));
+            assert_eq!(
+                loaded.get_resource(2, 5),
+                Some(ResourceTile::new(Resource::Iron, 250))
+            );
+            assert_eq!(loaded.entities.len(), 2);
+            assert!(loaded.is_explored(11, 7) && !loaded.is_explored(12, 7));
+            assert_eq!(loaded.generator(), None);
+            // Nothing got lost on the way if it saves to the same bytes
+            assert_eq!(encode(&loaded, player), bytes);
+        }
+    }
+
+    #[test]
+    fn round_trip_generated() {
+        let mut world = World::generate(3, TerrainSettings::default());
+        world.set_tile(-5, -5, Blocks::STONE);
+        let bytes = encode(&world, None);
+        let (loaded, _, _) = decode(&bytes).unwrap();
+        assert_eq!(loaded.size(), None);
+        assert_eq!(loaded.generator(), world.generator());
+        assert_eq!(encode(&loaded, None), bytes);
+    }
+
+    #[test]
+    fn upgrades_legacy_files() {
+        // A 2x2 world stored column by column, with the tile size written
+        // as a u64 and the old player block at (1, 0)
+        let mut legacy = 2u32.to_le_bytes().to_vec();
+        legacy.extend_from_slice(&48u64.to_le_bytes());
+        legacy.extend_from_slice(&[1, 0, 2, 3]);
+
+        let (world, player, format) = decode(&legacy).unwrap();
+        assert_eq!(format, Format::Legacy);
+        assert_eq!(player, None);
+        assert_eq!(world.size(), Some((2, 2)));
+        assert_eq!(world.tile_size, 48);
+        assert_eq!(world.get_tile(0, 0), Some(Blocks::STONE));
+        assert_eq!(world.get_tile(0, 1), Some(Blocks::GRASS));
+        assert_eq!(world.get_tile(1, 0), Some(Blocks::GRASS));
+        assert_eq!(world.get_tile(1, 1), Some(Blocks::WATER));
+
+        // The tile size was documented as a u32, which reads the same
+        let mut short = 2u32.to_le_bytes().to_vec();
+        short.extend_from_slice(&48u32.to_le_bytes());
+        short.extend_from_slice(&[1, 0, 2, 3]);
+        let (world_short, _, _) = decode(&short).unwrap();
+        assert_eq!(encode(&world_short, None), encode(&world, None));
+
+        let file = std::env::temp_dir().join(format!("legacy_{}.cade", std::process::id()));
+        let file_name = file.to_str().unwrap();
+        std::fs::write(file_name, &legacy).unwrap();
+        assert!(upgrade_file(file_name).unwrap());
+        let (upgraded, _, format) = decode(&std::fs::read(file_name).unwrap()).unwrap();
+        assert_eq!(format, Format::Versioned(VERSION));
+        assert_eq!(encode(&upgraded, None), encode(&world, None));
+        // Already up to date
+        assert!(!upgrade_file(file_name).unwrap());
+        std::fs::remove_file(file_name).unwrap();
+    }
+
+    #[test]
+    fn reads_version_4() {
+        // Tiles row by row, then a resource and no entities, uncompressed
+        let mut payload = vec![0, 1, 3, 2];
+        payload.extend_from_slice(&1u32.to_le_bytes());
+        payload.extend_from_slice(&1i32.to_le_bytes());
+        payload.extend_from_slice(&0i32.to_le_bytes());
+        payload.push(Resource::Coal.to_u8());
+        payload.extend_from_slice(&77u32.to_le_bytes());
+        payload.extend_from_slice(&0u32.to_le_bytes());
+
+        let (world, player, format) = decode(&old_file(4, 2, 2, FLAG_BOUNDED, &payload)).unwrap();
+        assert_eq!(format, Format::Versioned(4));
+        assert_eq!(player, None);
+        assert_eq!(world.size(), Some((2, 2)));
+        assert_eq!(world.get_tile(1, 0), Some(Blocks::STONE));
+        assert_eq!(world.get_tile(0, 1), Some(Blocks::WATER));
+        assert_eq!(world.get_tile(1, 1), Some(Blocks::GRASS));
+        assert_eq!(
+            world.get_resource(1, 0),
+            Some(ResourceTile::new(Resource::Coal, 77))
+        );
+    }
+
+    #[test]
+    fn rejects_broken_files() {
+        let bytes = encode(&sample_world(), Some(PLAYER));
+
+        assert!(matches!(decode(b"not a world"), Err(SaveError::BadMagic)));
+        assert!(matches!(decode(&[]), Err(SaveError::BadMagic)));
+
+        assert!(matches!(
+            decode(&bytes[..HEADER_LEN - 1]),
+            Err(SaveError::Truncated { part: "header", .. })
+        ));
+        // Cutting into the payload breaks the checksum first
+        assert!(matches!(
+            decode(&bytes[..bytes.len() - 1]),
+            Err(SaveError::ChecksumMismatch { .. })
+        ));
+
+        let mut flipped = bytes.clone();
+        flipped[HEADER_LEN + 20] ^= 1;
+        assert!(matches!(
+            decode(&flipped),
+            Err(SaveError::ChecksumMismatch { .. })
+        ));
+
+        // The checksum covers the header too
+        let mut header = bytes.clone();
+        header[6] ^= 1;
+        assert!(matches!(
+            decode(&header),
+            Err(SaveError::ChecksumMismatch { .. })
+        ));
+
+        // Too big to allocate, whether or not the checksum matches
+        let mut huge = bytes.clone();
+        huge[6..14].copy_from_slice(&[0xFF; 8]);
+        fix_checksum(&mut huge);
+        assert!(matches!(decode(&huge), Err(SaveError::TooLarge { .. })));
+        let huge = old_file(4, u32::MAX, u32::MAX, FLAG_BOUNDED, &[]);
+        assert!(matches!(decode(&huge), Err(SaveError::TooLarge { .. })));
+
+        let mut newer = bytes.clone();
+        newer[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            decode(&newer),
+            Err(SaveError::UnsupportedVersion(version)) if version == i64::from(VERSION + 1)
+        ));
+
+        // The first run of the first chunk no longer covers the whole chunk
+        let mut runs = encode(&World::new(2, 2), None);
+        runs[HEADER_LEN + 16..HEADER_LEN + 18].copy_from_slice(&1000u16.to_le_bytes());
+        fix_checksum(&mut runs);
+        assert!(matches!(
+            decode(&runs),
+            Err(SaveError::InvalidChunk { x: 0, y: 0 })
+        ));
+
+        // Block ids 5 to 7 were never saved
+        let payload = [0, 6, 0, 0];
+        assert!(matches!(
+            decode(&old_file(1, 2, 2, 0, &payload)),
+            Err(SaveError::InvalidBlock { index: 1, value: 6 })
+        ));
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+}
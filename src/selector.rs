@@ -1,17 +1,28 @@
+use crate::entity::Blueprint;
+use crate::mapfile::{self, ColorMap};
 use crate::state::*;
-use crate::world::Blocks;
+use crate::world::{Anchor, Blocks};
 use crate::World;
 use raylib::prelude::*;
 use std::os::raw::c_int;
 
 pub struct Selector {
-    pub x: usize,
-    pub y: usize,
+    pub x: i32,
+    pub y: i32,
+    // What P places
+    pub blueprint: Blueprint,
+    // First corner of the area C crops to, set with V
+    pub mark: Option<(i32, i32)>,
 }
 
 impl Selector {
     pub fn new() -> Self {
-        Selector { x: 0, y: 0 }
+        Selector {
+            x: 0,
+            y: 0,
+            blueprint: Blueprint::default(),
+            mark: None,
+        }
     }
 
     pub fn render(
@@ -59,29 +70,33 @@ impl Selector {
         )
     }
 
+    // Keeps the selector on the same tile after the map moves under it
+    fn shift(&mut self, dx: i32, dy: i32) {
+        self.x += dx;
+        self.y += dy;
+        self.mark = self.mark.map(|(x, y)| (x + dx, y + dy));
+    }
+
     pub fn mov(&mut self, state: &mut State, world: &mut World, camera: &mut Camera2D) {
         unsafe {
-            // Movement keys
+            // Movement keys, unbounded worlds grow as the selector moves into new chunks
             if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_K as c_int) {
-                if self.y > 0 {
-                    self.y = self.y.saturating_sub(1);
-                }
+                self.y -= 1;
             }
             if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_H as c_int) {
-                if self.x > 0 {
-                    self.x = self.x.saturating_sub(1);
-                }
+                self.x -= 1;
             }
             if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_J as c_int) {
-                if self.y + 1 < world.data[0].len() {
-                    self.y = self.y + 1;
-                }
+                self.y += 1;
             }
             if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_L as c_int) {
-                if self.x + 1 < world.data.len() {
-                    self.x = self.x + 1;
-                }
+                self.x += 1;
+            }
+            if let Some((width, height)) = world.size() {
+                self.x = self.x.clamp(0, width as i32 - 1);
+                self.y = self.y.clamp(0, height as i32 - 1);
             }
+            world.load_chunks_around(self.x, self.y, 1);
 
             if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_U as i32) {
                 state.change_view(View::Minimap);
@@ -95,15 +110,67 @@ impl Selector {
 
             // Block placement/removal
             if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_W as c_int) {
-                world.data[self.x][self.y] = Blocks::GRASS;
+                world.set_tile(self.x, self.y, Blocks::GRASS);
             }
             if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_R as i32) {
-                world.data[self.x][self.y] = Blocks::STONE;
+                world.set_tile(self.x, self.y, Blocks::STONE);
+            }
+
+            // Mine whatever ore is under the selector
+            if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_M as c_int) {
+                world.mine_ore(self.x, self.y);
+            }
+
+            // Buildings
+            if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_B as c_int) {
+                self.blueprint.next_kind();
+            }
+            if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_Q as c_int) {
+                self.blueprint.rotate();
+            }
+            if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_P as c_int) {
+                world.build(self.blueprint, self.x, self.y);
+            }
+            if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_X as c_int) {
+                world.demolish(self.x, self.y);
+            }
+
+            // Map size, E grows the map by 8 tiles on every side and C crops it
+            // to the rectangle between the mark and the selector
+            if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_E as c_int) {
+                let (_, _, width, height) = world.bounds().unwrap_or((0, 0, 0, 0));
+                let (dx, dy) = world.resize(width + 16, height + 16, Anchor::Center, Blocks::GRASS);
+                self.shift(dx, dy);
+                println!("Map is now {:?}", world.size());
+            }
+            if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_V as c_int) {
+                self.mark = Some((self.x, self.y));
+                println!("Mark: ({}, {})", self.x, self.y);
+            }
+            if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_C as c_int) {
+                if let Some((mark_x, mark_y)) = self.mark {
+                    let (x, y) = (mark_x.min(self.x), mark_y.min(self.y));
+                    let width = (mark_x - self.x).unsigned_abs() as usize + 1;
+                    let height = (mark_y - self.y).unsigned_abs() as usize + 1;
+                    let (dx, dy) = world.crop(x, y, width, height);
+                    self.shift(dx, dy);
+                    self.mark = None;
+                    println!("Map is now {:?}", world.size());
+                }
             }
 
             // Save world
             if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_S as c_int) {
-                let _ = world.data_to_file("data.cade");
+                state.save_requested = true;
+            }
+            // Export the terrain for editing outside the game
+            if ffi::IsKeyPressed(ffi::KeyboardKey::KEY_T as c_int) {
+                let colors = ColorMap::from_registry(&world.tiles);
+                if let Err(e) = mapfile::save_png(world, "map.png", &colors)
+                    .and_then(|_| mapfile::save_ascii(world, "map.txt"))
+                {
+                    println!("Couldn't export map: {}", e);
+                }
             }
 
             // Camera zoom adjustments
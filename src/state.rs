@@ -1,6 +1,10 @@
+use crate::autosave::{self, Autosave};
+use crate::daynight::DayCycle;
+use crate::light::LightMap;
 use crate::player::*;
 use crate::render::*;
 use crate::selector::*;
+use crate::storage::{FileStorage, Storage};
 use crate::world::*;
 
 #[derive(PartialEq)]
@@ -16,7 +20,17 @@ pub struct State {
     pub view: View,
     time_start: f64,
     delta_time: f32,
-    last_tick_time: f64, // Tracks the last time we printed a message
+    last_tick_time: f64, // Tracks the last time we ticked
+    // The game clock, counting ticks since the game started
+    pub ticks: u64,
+    pub day: DayCycle,
+    pub autosave: Autosave,
+    // Where the S key saves to, set save_requested to save at the end of the frame
+    pub storage: Box<dyn Storage>,
+    pub save_requested: bool,
+    // Lamps near the view are recast as soon as they're placed or something
+    // around them changes, the ambient level follows the time of day
+    pub light: LightMap,
 }
 
 impl State {
@@ -28,18 +42,41 @@ impl State {
             time_start: unsafe { raylib::ffi::GetTime() },
             delta_time: 0.0,
             last_tick_time: unsafe { raylib::ffi::GetTime() }, // Initialize to current time
+            ticks: 0,
+            day: DayCycle::default(),
+            autosave: Autosave::new(autosave::SLOTS, autosave::INTERVAL),
+            storage: Box::new(FileStorage::new(autosave::MANUAL_SAVE)),
+            save_requested: false,
+            light: LightMap::new(),
         }
     }
 
-    // Fixed game time tick
-    fn tick(&mut self) {
+    // Fixed game time tick, returns whether one happened this frame
+    fn tick(&mut self) -> bool {
         let current_time = unsafe { raylib::ffi::GetTime() };
 
         // Check if one second has elapsed since the last tick
         if current_time - self.last_tick_time >= 1.0 {
-            println!("One second has passed!");
+            self.ticks += 1;
             self.last_tick_time = current_time; // Reset the timer
+            return true;
         }
+        false
+    }
+
+    // How much the sun is shining right now, 0..1, for anything that runs on
+    // daylight
+    pub fn daylight(&self) -> f32 {
+        self.day.daylight(self.ticks)
+    }
+
+    pub fn ambient(&self) -> f32 {
+        self.day.ambient(self.ticks)
+    }
+
+    // Seconds the last frame took
+    pub fn delta_time(&self) -> f32 {
+        self.delta_time
     }
 
     // Per frame update
@@ -52,6 +89,11 @@ impl State {
     ) {
         self.delta_time = unsafe { raylib::ffi::GetFrameTime() };
 
+        if self.tick() {
+            let storage = self.storage.as_mut();
+            self.autosave.tick(storage, world, player.state(self.ticks));
+        }
+
         match self.view {
             View::Editor => {
                 renderer.render_t = RendererType::Editor;
@@ -66,6 +108,20 @@ impl State {
                 player.input_update(&mut renderer.camera, self, world);
             }
         }
+
+        // Far enough to cover the screen, or the FPS view's draw distance
+        let camera = &renderer.camera;
+        let half_screen = (self.screen_width as f32).hypot(self.screen_height as f32) / 2.0;
+        let reach = (half_screen / camera.zoom).max(VIEW_DISTANCE * world.tile_size as f32);
+        self.light.ambient = self.ambient();
+        self.light.update(world, camera.target, reach);
+
+        if self.save_requested {
+            self.save_requested = false;
+            if let Err(e) = self.storage.save(world, Some(player.state(self.ticks))) {
+                println!("Couldn't save: {}", e);
+            }
+        }
     }
 
     pub fn change_view(&mut self, view: View) {
@@ -0,0 +1,625 @@
+use crate::autosave;
+use crate::chunk::{Chunk, ChunkPos, CHUNK_SIZE};
+use crate::entity::{Direction, Entity, EntityId, EntityKind};
+use crate::player::PlayerState;
+use crate::resource::{Resource, ResourceTile};
+use crate::save::{self, SaveError};
+use crate::world::{Blocks, World};
+use rusqlite::{params, Connection, OptionalExtension};
+
+// Somewhere a world can be saved to and loaded back from
+pub trait Storage {
+    fn save(&mut self, world: &mut World, player: Option<PlayerState>) -> Result<(), SaveError>;
+    fn load(&mut self) -> Result<(World, Option<PlayerState>), SaveError>;
+
+    // Saves for the autosave, which cycles through slots 0..autosave::SLOTS.
+    // Storage that can't be left half written can ignore the slot and save
+    // in place.
+    fn autosave(
+        &mut self,
+        _slot: usize,
+        world: &mut World,
+        player: Option<PlayerState>,
+    ) -> Result<(), SaveError> {
+        self.save(world, player)
+    }
+}
+
+// A single .cade file, rewritten in full every save
+pub struct FileStorage {
+    pub file_name: String,
+}
+
+impl FileStorage {
+    pub fn new(file_name: &str) -> Self {
+        FileStorage {
+            file_name: file_name.to_string(),
+        }
+    }
+}
+
+impl Storage for FileStorage {
+    fn save(&mut self, world: &mut World, player: Option<PlayerState>) -> Result<(), SaveError> {
+        world.data_to_file(&self.file_name, player)?;
+        world.clear_dirty();
+        Ok(())
+    }
+
+    fn load(&mut self) -> Result<(World, Option<PlayerState>), SaveError> {
+        World::from_file(&self.file_name)
+    }
+
+    // A crash halfway through a save could leave the file unreadable, so
+    // autosaves go to their own files instead, see autosave::load_newest
+    fn autosave(
+        &mut self,
+        slot: usize,
+        world: &mut World,
+        player: Option<PlayerState>,
+    ) -> Result<(), SaveError> {
+        world.data_to_file(&autosave::slot_file(slot), player)
+    }
+}
+
+// Bumped whenever the tables change
+const SCHEMA_VERSION: i64 = 4;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS meta (
+        key TEXT PRIMARY KEY,
+        value INTEGER NOT NULL
+    );
+    -- The seed and terrain settings, laid out the same way as in a .cade
+    -- header. Worlds that aren't generated have no row.
+    CREATE TABLE IF NOT EXISTS generator (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        settings BLOB NOT NULL
+    );
+    -- tiles holds CHUNK_SIZE * CHUNK_SIZE block ids, row by row, and
+    -- explored one bit per tile in the same order, lowest bit first
+    CREATE TABLE IF NOT EXISTS chunks (
+        x INTEGER NOT NULL,
+        y INTEGER NOT NULL,
+        tiles BLOB NOT NULL,
+        explored BLOB,
+        PRIMARY KEY (x, y)
+    );
+    CREATE TABLE IF NOT EXISTS resources (
+        x INTEGER NOT NULL,
+        y INTEGER NOT NULL,
+        kind INTEGER NOT NULL,
+        amount INTEGER NOT NULL,
+        PRIMARY KEY (x, y)
+    );
+    CREATE TABLE IF NOT EXISTS entities (
+        id INTEGER PRIMARY KEY,
+        kind INTEGER NOT NULL,
+        x INTEGER NOT NULL,
+        y INTEGER NOT NULL,
+        direction INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS player (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        x REAL NOT NULL,
+        y REAL NOT NULL,
+        angle REAL NOT NULL,
+        ticks INTEGER NOT NULL DEFAULT 0
+    );
+";
+
+// A SQLite database. After the first save only the chunks the world marked
+// as dirty are written, buildings and the player are small enough to be
+// rewritten every time.
+pub struct SqliteStorage {
+    connection: Connection,
+    // Whether the database matches the world as of the last save or load,
+    // until then every chunk has to be written
+    synced: bool,
+}
+
+impl SqliteStorage {
+    pub fn open(file_name: &str) -> Result<Self, SaveError> {
+        Self::with_connection(Connection::open(file_name)?)
+    }
+
+    pub fn in_memory() -> Result<Self, SaveError> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(connection: Connection) -> Result<Self, SaveError> {
+        connection.execute_batch(SCHEMA)?;
+        if let Some(version) = meta(&connection, "version")? {
+            if !(1..=SCHEMA_VERSION).contains(&version) {
+                return Err(SaveError::UnsupportedVersion(version));
+            }
+            // Version 1 had nothing explored and version 2 didn't keep the
+            // game clock. Tables the old database didn't have at all were
+            // just created with every column.
+            for (table, column, definition) in [
+                ("chunks", "explored", "BLOB"),
+                ("player", "ticks", "INTEGER NOT NULL DEFAULT 0"),
+            ] {
+                if !has_column(&connection, table, column)? {
+                    connection.execute_batch(&format!(
+                        "ALTER TABLE {} ADD COLUMN {} {};",
+                        table, column, definition
+                    ))?;
+                }
+            }
+            // Up to version 3 the generator was a blob in meta
+            connection.execute_batch(
+                "INSERT OR REPLACE INTO generator (id, settings)
+                     SELECT 0, value FROM meta WHERE key = 'generator';
+                 DELETE FROM meta WHERE key = 'generator';",
+            )?;
+            connection.execute(
+                "UPDATE meta SET value = ?1 WHERE key = 'version'",
+                [SCHEMA_VERSION],
+            )?;
+        }
+        Ok(SqliteStorage {
+            connection,
+            synced: false,
+        })
+    }
+
+    // Whether anything has been saved to the database yet
+    pub fn is_empty(&self) -> Result<bool, SaveError> {
+        Ok(meta(&self.connection, "version")?.is_none())
+    }
+}
+
+fn meta(connection: &Connection, key: &str) -> Result<Option<i64>, SaveError> {
+    Ok(connection
+        .query_row("SELECT value FROM meta WHERE key = ?1", [key], |row| {
+            row.get(0)
+        })
+        .optional()?)
+}
+
+fn has_column(connection: &Connection, table: &str, column: &str) -> Result<bool, SaveError> {
+    let count: i64 = connection.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = ?2",
+        [table, column],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+fn write_chunk(
+    transaction: &rusqlite::Transaction,
+    pos: ChunkPos,
+    chunk: &Chunk,
+) -> Result<(), SaveError> {
+    let (origin_x, origin_y) = pos.origin();
+    let mut tiles = Vec::with_capacity(CHUNK_SIZE * CHUNK_SIZE);
+    let mut explored = vec![0u8; CHUNK_SIZE * CHUNK_SIZE / 8];
+    for j in 0..CHUNK_SIZE {
+        for i in 0..CHUNK_SIZE {
+            tiles.push(chunk.get(i, j).to_u8());
+            if chunk.is_explored(i, j) {
+                let index = j * CHUNK_SIZE + i;
+                explored[index / 8] |= 1 << (index % 8);
+            }
+        }
+    }
+    transaction.execute(
+        "INSERT OR REPLACE INTO chunks (x, y, tiles, explored) VALUES (?1, ?2, ?3, ?4)",
+        params![pos.x, pos.y, tiles, explored],
+    )?;
+
+    let size = CHUNK_SIZE as i32;
+    transaction.execute(
+        "DELETE FROM resources WHERE x >= ?1 AND x < ?2 AND y >= ?3 AND y < ?4",
+        params![origin_x, origin_x + size, origin_y, origin_y + size],
+    )?;
+    let mut insert = transaction
+        .prepare_cached("INSERT INTO resources (x, y, kind, amount) VALUES (?1, ?2, ?3, ?4)")?;
+    for i in 0..CHUNK_SIZE {
+        for j in 0..CHUNK_SIZE {
+            if let Some(resource) = chunk.get_resource(i, j) {
+                insert.execute(params![
+                    origin_x + i as i32,
+                    origin_y + j as i32,
+                    resource.kind.to_u8(),
+                    resource.amount
+                ])?;
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Storage for SqliteStorage {
+    fn save(&mut self, world: &mut World, player: Option<PlayerState>) -> Result<(), SaveError> {
+        let transaction = self.connection.transaction()?;
+
+        let (width, height) = match world.size() {
+            Some((width, height)) => (width as i64, height as i64),
+            None => (-1, -1),
+        };
+        for (key, value) in [
+            ("version", SCHEMA_VERSION),
+            ("tile_size", world.tile_size as i64),
+            ("width", width),
+            ("height", height),
+        ] {
+            transaction.execute(
+                "INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)",
+                params![key, value],
+            )?;
+        }
+        match world.generator() {
+            Some(generator) => transaction.execute(
+                "INSERT OR REPLACE INTO generator (id, settings) VALUES (0, ?1)",
+                [&save::encode_generator(&generator)[..]],
+            )?,
+            None => transaction.execute("DELETE FROM generator", [])?,
+        };
+
+        let mut written = 0;
+        if self.synced {
+            for &pos in world.dirty_chunks() {
+                if let Some(chunk) = world.chunk(pos) {
+                    write_chunk(&transaction, pos, chunk)?;
+                    written += 1;
+                }
+            }
+        } else {
+            transaction.execute("DELETE FROM chunks", [])?;
+            transaction.execute("DELETE FROM resources", [])?;
+            for (&pos, chunk) in world.chunks() {
+                write_chunk(&transaction, pos, chunk)?;
+                written += 1;
+            }
+        }
+
+        // Chunks the world no longer has, e.g. after cropping
+        let stale: Vec<(i32, i32)> = transaction
+            .prepare("SELECT x, y FROM chunks")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .filter(|&(x, y)| world.chunk(ChunkPos::new(x, y)).is_none())
+            .collect();
+        for (x, y) in stale {
+            let (origin_x, origin_y) = ChunkPos::new(x, y).origin();
+            let size = CHUNK_SIZE as i32;
+            transaction.execute("DELETE FROM chunks WHERE x = ?1 AND y = ?2", params![x, y])?;
+            transaction.execute(
+                "DELETE FROM resources WHERE x >= ?1 AND x < ?2 AND y >= ?3 AND y < ?4",
+                params![origin_x, origin_x + size, origin_y, origin_y + size],
+            )?;
+        }
+
+        transaction.execute("DELETE FROM entities", [])?;
+        for entity in world.entities.iter() {
+            transaction.execute(
+                "INSERT INTO entities (id, kind, x, y, direction) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    entity.id.0,
+                    entity.kind.to_u8(),
+                    entity.x,
+                    entity.y,
+                    entity.direction.to_u8()
+                ],
+            )?;
+        }
+
+        if let Some(player) = player {
+            transaction.execute(
+                "INSERT OR REPLACE INTO player (id, x, y, angle, ticks) VALUES (0, ?1, ?2, ?3, ?4)",
+                params![player.x, player.y, player.angle, player.ticks as i64],
+            )?;
+        }
+
+        transaction.commit()?;
+        world.clear_dirty();
+        self.synced = true;
+        println!("World data ({} chunks written) saved to database", written);
+        Ok(())
+    }
+
+    fn load(&mut self) -> Result<(World, Option<PlayerState>), SaveError> {
+        let connection = &self.connection;
+        let tile_size = meta(connection, "tile_size")?.unwrap_or(64) as usize;
+        let width = meta(connection, "width")?.unwrap_or(-1);
+        let height = meta(connection, "height")?.unwrap_or(-1);
+
+        let mut world = if width >= 0 && height >= 0 {
+            World::new(width as usize, height as usize)
+        } else {
+            World::empty(tile_size)
+        };
+        world.tile_size = tile_size;
+        let generator: Option<Vec<u8>> = connection
+            .query_row("SELECT settings FROM generator WHERE id = 0", [], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        if let Some(generator) = generator {
+            world.set_generator(Some(save::decode_generator(&generator)?));
+        }
+
+        let mut chunks = connection.prepare("SELECT x, y, tiles, explored FROM chunks")?;
+        let mut rows = chunks.query([])?;
+        while let Some(row) = rows.next()? {
+            let pos = ChunkPos::new(row.get(0)?, row.get(1)?);
+            let tiles: Vec<u8> = row.get(2)?;
+            if tiles.len() != CHUNK_SIZE * CHUNK_SIZE {
+                return Err(SaveError::Truncated {
+                    part: "chunk tiles",
+                    expected: CHUNK_SIZE * CHUNK_SIZE,
+                    found: tiles.len(),
+                });
+            }
+
+            let mut chunk = Chunk::new(Blocks::GRASS);
+            for (index, &value) in tiles.iter().enumerate() {
+                let block = world
+                    .tiles
+                    .block(value)
+                    .ok_or(SaveError::InvalidBlock { index, value })?;
+                chunk.set(index % CHUNK_SIZE, index / CHUNK_SIZE, block);
+            }
+
+            // Missing for chunks saved before exploring was tracked
+            let explored: Option<Vec<u8>> = row.get(3)?;
+            if let Some(explored) = explored {
+                if explored.len() != CHUNK_SIZE * CHUNK_SIZE / 8 {
+                    return Err(SaveError::InvalidChunk { x: pos.x, y: pos.y });
+                }
+                for index in 0..CHUNK_SIZE * CHUNK_SIZE {
+                    let bit = explored[index / 8] & (1 << (index % 8)) != 0;
+                    chunk.set_explored(index % CHUNK_SIZE, index / CHUNK_SIZE, bit);
+                }
+            }
+            world.insert_chunk(pos, chunk);
+        }
+
+        let mut resources = connection.prepare("SELECT x, y, kind, amount FROM resources")?;
+        let mut rows = resources.query([])?;
+        while let Some(row) = rows.next()? {
+            let value: u8 = row.get(2)?;
+            let kind = Resource::from_u8(value).ok_or(SaveError::InvalidResource { value })?;
+            world.set_resource(
+                row.get(0)?,
+                row.get(1)?,
+                Some(ResourceTile::new(kind, row.get(3)?)),
+            );
+        }
+
+        let mut entities = connection.prepare("SELECT id, kind, x, y, direction FROM entities")?;
+        let mut rows = entities.query([])?;
+        while let Some(row) = rows.next()? {
+            let value: u8 = row.get(1)?;
+            let kind = EntityKind::from_u8(value).ok_or(SaveError::InvalidEntity { value })?;
+            let value: u8 = row.get(4)?;
+            let direction = Direction::from_u8(value).ok_or(SaveError::InvalidEntity { value })?;
+            world.entities.insert(Entity {
+                id: EntityId(row.get(0)?),
+                kind,
+                x: row.get(2)?,
+                y: row.get(3)?,
+                direction,
+            })?;
+        }
+
+        let player = connection
+            .query_row(
+                "SELECT x, y, angle, ticks FROM player WHERE id = 0",
+                [],
+                |row| {
+                    Ok(PlayerState {
+                        x: row.get(0)?,
+                        y: row.get(1)?,
+                        angle: row.get(2)?,
+                        ticks: row.get::<_, i64>(3)? as u64,
+                    })
+                },
+            )
+            .optional()?;
+
+        world.clear_dirty();
+        self.synced = true;
+        println!(
+            "World data (bounds: {:?}, tile_size: {}) loaded from database",
+            world.bounds(),
+            world.tile_size
+        );
+        Ok((world, player))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::autosave::Autosave;
+    use crate::terrain::TerrainSettings;
+
+    const PLAYER: PlayerState = PlayerState {
+        x: 96.0,
+        y: -32.5,
+        angle: 270.0,
+        ticks: 1234,
+    };
+
+    // A generated world with a bit of everything that gets saved
+    fn sample_world() -> World {
+        let mut world = World::generate(7, TerrainSettings::default());
+        world.set_tile(3, 3, Blocks::STONE);
+        world.set_tile(-20, 12, Blocks::WATER);
+        world.set_resource(4, 4, Some(ResourceTile::new(Resource::Coal, 42)));
+        world
+            .place_entity(EntityKind::Drill, 5, 5, Direction::West)
+            .unwrap();
+        world.explore(3, 3);
+        world.explore(-20, 12);
+        world
+    }
+
+    // Every world saves to the same bytes as long as nothing in it differs
+    fn assert_same(loaded: &World, world: &World) {
+        assert_eq!(save::encode(loaded, None), save::encode(world, None));
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut db = SqliteStorage::in_memory().unwrap();
+        assert!(db.is_empty().unwrap());
+        let mut world = sample_world();
+        db.save(&mut world, Some(PLAYER)).unwrap();
+        assert!(!db.is_empty().unwrap());
+
+        let (loaded, player) = db.load().unwrap();
+        assert_same(&loaded, &world);
+        assert_eq!(loaded.generator(), world.generator());
+        assert_eq!(player, Some(PLAYER));
+    }
+
+    #[test]
+    fn later_saves_only_write_what_changed() {
+        let mut db = SqliteStorage::in_memory().unwrap();
+        let mut world = sample_world();
+        db.save(&mut world, Some(PLAYER)).unwrap();
+        assert_eq!(world.dirty_chunks().count(), 0);
+
+        // One chunk that was saved before and one that's new
+        world.set_tile(3, 4, Blocks::STONE);
+        world.set_resource(3, 3, None);
+        world.set_tile(-70, -70, Blocks::SAND);
+        assert_eq!(world.dirty_chunks().count(), 2);
+        // Saving without a player keeps the last one
+        db.save(&mut world, None).unwrap();
+        let (loaded, player) = db.load().unwrap();
+        assert_same(&loaded, &world);
+        assert_eq!(player, Some(PLAYER));
+
+        // Chunks cropped off are deleted
+        let mut world = loaded;
+        world.crop(0, 0, 10, 10);
+        db.save(&mut world, None).unwrap();
+        let (loaded, _) = db.load().unwrap();
+        assert_eq!(loaded.size(), Some((10, 10)));
+        assert_eq!(loaded.chunks().count(), 1);
+        assert_same(&loaded, &world);
+    }
+
+    #[test]
+    fn upgrades_old_schemas() {
+        // Version 1 had no explored column and no game clock
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE meta (key TEXT PRIMARY KEY, value INTEGER NOT NULL);
+                 CREATE TABLE chunks (
+                     x INTEGER NOT NULL,
+                     y INTEGER NOT NULL,
+                     tiles BLOB NOT NULL,
+                     PRIMARY KEY (x, y)
+                 );
+                 CREATE TABLE player (
+                     id INTEGER PRIMARY KEY CHECK (id = 0),
+                     x REAL NOT NULL,
+                     y REAL NOT NULL,
+                     angle REAL NOT NULL
+                 );
+                 INSERT INTO meta VALUES
+                     ('version', 1), ('tile_size', 64), ('width', 32), ('height', 32);
+                 INSERT INTO player VALUES (0, 1.0, 2.0, 90.0);",
+            )
+            .unwrap();
+        connection
+            .execute(
+                "INSERT INTO chunks VALUES (0, 0, ?1)",
+                [vec![Blocks::STONE.to_u8(); CHUNK_SIZE * CHUNK_SIZE]],
+            )
+            .unwrap();
+
+        let mut db = SqliteStorage::with_connection(connection).unwrap();
+        assert_eq!(
+            meta(&db.connection, "version").unwrap(),
+            Some(SCHEMA_VERSION)
+        );
+        let (mut world, player) = db.load().unwrap();
+        assert_eq!(world.get_tile(3, 3), Some(Blocks::STONE));
+        assert!(!world.is_explored(3, 3));
+        let player = player.unwrap();
+        assert_eq!((player.angle, player.ticks), (90.0, 0));
+
+        world.explore(3, 3);
+        db.save(&mut world, Some(PLAYER)).unwrap();
+        let (loaded, player) = db.load().unwrap();
+        assert!(loaded.is_explored(3, 3));
+        assert_eq!(player, Some(PLAYER));
+    }
+
+    #[test]
+    fn moves_the_generator_out_of_meta() {
+        let world = World::generate(7, TerrainSettings::default());
+        let generator = save::encode_generator(&world.generator().unwrap());
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE meta (key TEXT PRIMARY KEY, value INTEGER NOT NULL);
+                 INSERT INTO meta VALUES
+                     ('version', 3), ('tile_size', 64), ('width', -1), ('height', -1);",
+            )
+            .unwrap();
+        connection
+            .execute(
+                "INSERT INTO meta VALUES ('generator', ?1)",
+                [&generator[..]],
+            )
+            .unwrap();
+
+        let mut db = SqliteStorage::with_connection(connection).unwrap();
+        assert_eq!(meta(&db.connection, "generator").unwrap(), None);
+        let (loaded, _) = db.load().unwrap();
+        assert_eq!(loaded.generator(), world.generator());
+    }
+
+    #[test]
+    fn rejects_newer_schemas() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE meta (key TEXT PRIMARY KEY, value INTEGER NOT NULL);
+                 INSERT INTO meta VALUES ('version', 99);",
+            )
+            .unwrap();
+        assert!(matches!(
+            SqliteStorage::with_connection(connection),
+            Err(SaveError::UnsupportedVersion(99))
+        ));
+
+        // Too big for a .cade version, which mustn't wrap around to a valid one
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE meta (key TEXT PRIMARY KEY, value INTEGER NOT NULL);
+                 INSERT INTO meta VALUES ('version', 65537);",
+            )
+            .unwrap();
+        assert!(matches!(
+            SqliteStorage::with_connection(connection),
+            Err(SaveError::UnsupportedVersion(65537))
+        ));
+    }
+
+    #[test]
+    fn autosaves_into_the_database() {
+        let mut db = SqliteStorage::in_memory().unwrap();
+        let mut autosave = Autosave::new(2, 3);
+        let mut world = sample_world();
+        for _ in 0..2 {
+            autosave.tick(&mut db, &mut world, PLAYER);
+        }
+        assert!(db.is_empty().unwrap());
+
+        autosave.tick(&mut db, &mut world, PLAYER);
+        let (loaded, player) = db.load().unwrap();
+        assert_same(&loaded, &world);
+        assert_eq!(player, Some(PLAYER));
+    }
+}
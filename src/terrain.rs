@@ -0,0 +1,239 @@
+use crate::chunk::{Chunk, ChunkPos, CHUNK_SIZE};
+use crate::resource::{Resource, ResourceTile};
+use crate::world::Blocks;
+
+// Knobs for World::generate. Levels are compared against fractal noise that
+// lands roughly in -1..1, scales are in tiles per noise cell.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TerrainSettings {
+    pub scale: f32,
+    pub octaves: u32,
+    pub persistence: f32,
+    pub lacunarity: f32,
+    pub water_level: f32,
+    pub sand_level: f32,
+    pub stone_level: f32,
+    pub ore_scale: f32,
+    pub ore_threshold: f32,
+    // Amount of ore on a tile at the edge of a patch, the middle holds up to 5x more
+    pub ore_richness: u32,
+}
+
+impl Default for TerrainSettings {
+    fn default() -> Self {
+        TerrainSettings {
+            scale: 48.0,
+            octaves: 4,
+            persistence: 0.5,
+            lacunarity: 2.0,
+            water_level: -0.3,
+            sand_level: -0.2,
+            stone_level: 0.35,
+            ore_scale: 16.0,
+            ore_threshold: 0.4,
+            ore_richness: 300,
+        }
+    }
+}
+
+// Each noise layer gets its own seed so they don't line up with each other
+const ELEVATION_LAYER: u64 = 0;
+const ORE_LAYERS: [(u64, Resource); 4] = [
+    (1, Resource::Iron),
+    (2, Resource::Copper),
+    (3, Resource::Coal),
+    (4, Resource::Stone),
+];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Generator {
+    pub seed: u64,
+    pub settings: TerrainSettings,
+}
+
+impl Generator {
+    pub fn new(seed: u64, settings: TerrainSettings) -> Self {
+        Generator { seed, settings }
+    }
+
+    pub fn tile(&self, x: i32, y: i32) -> Blocks {
+        let s = &self.settings;
+        let elevation = self.fbm(ELEVATION_LAYER, x as f32 / s.scale, y as f32 / s.scale);
+
+        if elevation < s.water_level {
+            return Blocks::WATER;
+        }
+        if elevation > s.stone_level {
+            return Blocks::STONE;
+        }
+
+        if elevation < s.sand_level {
+            Blocks::SAND
+        } else {
+            Blocks::GRASS
+        }
+    }
+
+    // Ore patches only show up on open ground, the strongest layer wins
+    pub fn resource(&self, x: i32, y: i32, block: Blocks) -> Option<ResourceTile> {
+        if block != Blocks::GRASS && block != Blocks::SAND {
+            return None;
+        }
+
+        let s = &self.settings;
+        let (value, kind) = ORE_LAYERS
+            .iter()
+            .map(|&(layer, kind)| {
+                let value = self.fbm(layer, x as f32 / s.ore_scale, y as f32 / s.ore_scale);
+                (value, kind)
+            })
+            .filter(|&(value, _)| value > s.ore_threshold)
+            .max_by(|a, b| a.0.total_cmp(&b.0))?;
+
+        // Patches get richer towards their middle
+        let depth = (value - s.ore_threshold) / (1.0 - s.ore_threshold);
+        let amount = s.ore_richness as f32 * (1.0 + 4.0 * depth.clamp(0.0, 1.0));
+        Some(ResourceTile::new(kind, amount as u32))
+    }
+
+    pub fn chunk(&self, pos: ChunkPos) -> Chunk {
+        let (origin_x, origin_y) = pos.origin();
+        let mut chunk = Chunk::new(Blocks::GRASS);
+        for i in 0..CHUNK_SIZE {
+            for j in 0..CHUNK_SIZE {
+                let (x, y) = (origin_x + i as i32, origin_y + j as i32);
+                let block = self.tile(x, y);
+                chunk.set(i, j, block);
+                chunk.set_resource(i, j, self.resource(x, y, block));
+            }
+        }
+        chunk
+    }
+
+    // Layered gradient noise, normalised back into -1..1
+    fn fbm(&self, layer: u64, x: f32, y: f32) -> f32 {
+        let s = &self.settings;
+        let seed = self.seed ^ layer.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        let mut total = 0.0;
+        let mut amplitude = 1.0;
+        let mut frequency = 1.0;
+        let mut max = 0.0;
+
+        for octave in 0..s.octaves {
+            total += amplitude
+                * gradient_noise(
+                    seed.wrapping_add(octave as u64),
+                    x * frequency,
+                    y * frequency,
+                );
+            max += amplitude;
+            amplitude *= s.persistence;
+            frequency *= s.lacunarity;
+        }
+
+        if max > 0.0 {
+            total / max
+        } else {
+            0.0
+        }
+    }
+}
+
+// Perlin style gradient noise on the integer lattice
+pub fn gradient_noise(seed: u64, x: f32, y: f32) -> f32 {
+    let x0 = x.floor();
+    let y0 = y.floor();
+    let fx = x - x0;
+    let fy = y - y0;
+    let (ix, iy) = (x0 as i32, y0 as i32);
+
+    let corner = |cx: i32, cy: i32, dx: f32, dy: f32| {
+        let turn = (hash(seed, cx, cy) >> 40) as f32 / (1u64 << 24) as f32;
+        let angle = turn * std::f32::consts::TAU;
+        angle.cos() * dx + angle.sin() * dy
+    };
+
+    let n00 = corner(ix, iy, fx, fy);
+    let n10 = corner(ix + 1, iy, fx - 1.0, fy);
+    let n01 = corner(ix, iy + 1, fx, fy - 1.0);
+    let n11 = corner(ix + 1, iy + 1, fx - 1.0, fy - 1.0);
+
+    let u = fade(fx);
+    let v = fade(fy);
+    let top = n00 + (n10 - n00) * u;
+    let bottom = n01 + (n11 - n01) * u;
+
+    // Gradient noise peaks at sqrt(0.5), stretch it out to -1..1
+    (top + (bottom - top) * v) * std::f32::consts::SQRT_2
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+// Mixes a seed and a lattice point into 64 well scrambled bits (splitmix64)
+pub fn hash(seed: u64, x: i32, y: i32) -> u64 {
+    let mut z = seed
+        ^ (x as u32 as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+        ^ (y as u32 as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::save;
+    use crate::world::World;
+
+    const CHUNKS: [(i32, i32); 4] = [(0, 0), (-3, 7), (40, -12), (-100, -100)];
+
+    #[test]
+    fn same_seed_gives_same_chunks() {
+        let settings = TerrainSettings::default();
+        for (x, y) in CHUNKS {
+            let pos = ChunkPos::new(x, y);
+            let first = Generator::new(42, settings).chunk(pos);
+            let second = Generator::new(42, settings).chunk(pos);
+            assert!(first == second, "chunk ({}, {}) came out differently", x, y);
+        }
+    }
+
+    #[test]
+    fn different_seeds_give_different_chunks() {
+        let settings = TerrainSettings::default();
+        let differs = CHUNKS.iter().any(|&(x, y)| {
+            let pos = ChunkPos::new(x, y);
+            Generator::new(1, settings).chunk(pos) != Generator::new(2, settings).chunk(pos)
+        });
+        assert!(differs);
+    }
+
+    #[test]
+    fn loaded_world_keeps_generating() {
+        let settings = TerrainSettings {
+            water_level: -0.1,
+            ..TerrainSettings::default()
+        };
+        let mut world = World::generate(7, settings);
+        let (mut loaded, _, _) = save::decode(&save::encode(&world, None)).unwrap();
+        assert_eq!(loaded.generator(), world.generator());
+
+        // Chunks nobody visited before saving
+        for (x, y) in CHUNKS {
+            let pos = ChunkPos::new(x, y);
+            let expected = world.load_chunk(pos).clone();
+            assert!(
+                *loaded.load_chunk(pos) == expected,
+                "chunk ({}, {}) wasn't generated after loading",
+                x,
+                y
+            );
+        }
+        let grass = Chunk::new(Blocks::GRASS);
+        assert!(CHUNKS
+            .iter()
+            .any(|&(x, y)| *loaded.load_chunk(ChunkPos::new(x, y)) != grass));
+    }
+}
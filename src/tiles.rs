@@ -0,0 +1,239 @@
+use crate::world::Blocks;
+use raylib::prelude::*;
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::io;
+
+// Built in copy of tiles.ron so a world can always be created
+const DEFAULT_TILES: &str = include_str!("../tiles.ron");
+
+// Id of the placeholder prototype for unknown tiles, which tiles.ron can't use
+pub const MISSING_ID: u8 = 255;
+
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
+pub struct AtlasRect {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl From<AtlasRect> for Rectangle {
+    fn from(rect: AtlasRect) -> Self {
+        Rectangle {
+            x: rect.x,
+            y: rect.y,
+            width: rect.width,
+            height: rect.height,
+        }
+    }
+}
+
+// Everything the game knows about a kind of tile
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct TilePrototype {
+    pub id: u8,
+    pub name: String,
+    pub atlas: AtlasRect,
+    pub minimap_color: (u8, u8, u8),
+    pub glyph: char,
+    pub solid: bool,
+    pub opaque: bool,
+    #[serde(default = "default_walk_speed")]
+    pub walk_speed: f32,
+}
+
+fn default_walk_speed() -> f32 {
+    1.0
+}
+
+impl TilePrototype {
+    pub fn color(&self) -> Color {
+        let (r, g, b) = self.minimap_color;
+        Color::new(r, g, b, 255)
+    }
+}
+
+#[derive(Debug)]
+pub enum RegistryError {
+    Io(io::Error),
+    Parse(ron::error::SpannedError),
+    DuplicateId(u8),
+    ReservedId(u8),
+    DuplicateName(String),
+    DuplicateGlyph(char),
+    // The tile's atlas rect is less than a pixel wide or tall
+    EmptyAtlas(String),
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RegistryError::Io(e) => write!(f, "io error: {}", e),
+            RegistryError::Parse(e) => write!(f, "couldn't parse tile prototypes: {}", e),
+            RegistryError::DuplicateId(id) => write!(f, "tile id {} is defined twice", id),
+            RegistryError::ReservedId(id) => write!(f, "tile id {} is reserved", id),
+            RegistryError::DuplicateName(name) => {
+                write!(f, "tile name {:?} is defined twice", name)
+            }
+            RegistryError::DuplicateGlyph(glyph) => {
+                write!(f, "tile glyph {:?} is used twice", glyph)
+            }
+            RegistryError::EmptyAtlas(name) => {
+                write!(f, "tile {:?} has an empty atlas rect", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+impl From<io::Error> for RegistryError {
+    fn from(e: io::Error) -> Self {
+        RegistryError::Io(e)
+    }
+}
+
+// Tile prototypes indexed by their id
+#[derive(Clone, Debug)]
+pub struct TileRegistry {
+    prototypes: Vec<Option<TilePrototype>>,
+    missing: TilePrototype,
+}
+
+impl Default for TileRegistry {
+    fn default() -> Self {
+        Self::parse(DEFAULT_TILES).expect("built in tiles.ron is invalid")
+    }
+}
+
+impl TileRegistry {
+    pub fn parse(source: &str) -> Result<Self, RegistryError> {
+        let list: Vec<TilePrototype> = ron::from_str(source).map_err(RegistryError::Parse)?;
+
+        let mut prototypes: Vec<Option<TilePrototype>> = vec![None; 256];
+        for prototype in list {
+            if prototype.id == MISSING_ID {
+                return Err(RegistryError::ReservedId(prototype.id));
+            }
+            // Written this way round so NaN is rejected too
+            let atlas = &prototype.atlas;
+            if !(atlas.width >= 1.0 && atlas.height >= 1.0) {
+                return Err(RegistryError::EmptyAtlas(prototype.name));
+            }
+            if prototypes
+                .iter()
+                .flatten()
+                .any(|p| p.name == prototype.name)
+            {
+                return Err(RegistryError::DuplicateName(prototype.name));
+            }
+            if prototypes
+                .iter()
+                .flatten()
+                .any(|p| p.glyph == prototype.glyph)
+            {
+                return Err(RegistryError::DuplicateGlyph(prototype.glyph));
+            }
+            let slot = &mut prototypes[prototype.id as usize];
+            if slot.is_some() {
+                return Err(RegistryError::DuplicateId(prototype.id));
+            }
+            *slot = Some(prototype);
+        }
+
+        Ok(TileRegistry {
+            prototypes,
+            // Drawn with the player sprite so unknown tiles stand out
+            missing: TilePrototype {
+                id: MISSING_ID,
+                name: String::from("missing"),
+                atlas: AtlasRect {
+                    x: 0.0,
+                    y: 0.0,
+                    width: 32.0,
+                    height: 32.0,
+                },
+                minimap_color: (255, 0, 255),
+                glyph: '?',
+                solid: true,
+                opaque: true,
+                walk_speed: 0.0,
+            },
+        })
+    }
+
+    pub fn from_file(file_name: &str) -> Result<Self, RegistryError> {
+        Self::parse(&fs::read_to_string(file_name)?)
+    }
+
+    // Unknown blocks get a placeholder prototype instead of panicking
+    pub fn get(&self, block: Blocks) -> &TilePrototype {
+        self.prototypes[block.to_u8() as usize]
+            .as_ref()
+            .unwrap_or(&self.missing)
+    }
+
+    // The block with this id, if it's been defined
+    pub fn block(&self, id: u8) -> Option<Blocks> {
+        self.prototypes[id as usize].as_ref().map(|_| Blocks(id))
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<Blocks> {
+        self.iter()
+            .find(|prototype| prototype.name == name)
+            .map(|prototype| Blocks(prototype.id))
+    }
+
+    pub fn by_glyph(&self, glyph: char) -> Option<Blocks> {
+        self.iter()
+            .find(|prototype| prototype.glyph == glyph)
+            .map(|prototype| Blocks(prototype.id))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &TilePrototype> {
+        self.prototypes.iter().flatten()
+    }
+
+    pub fn is_solid(&self, block: Blocks) -> bool {
+        self.get(block).solid
+    }
+
+    pub fn is_opaque(&self, block: Blocks) -> bool {
+        self.get(block).opaque
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile(id: u8, name: &str, glyph: char, width: f32, height: f32) -> String {
+        format!(
+            "(id: {}, name: {:?}, atlas: (x: 0, y: 0, width: {:?}, height: {:?}), \
+             minimap_color: (0, 0, 0), glyph: {:?}, solid: false, opaque: false)",
+            id, name, width, height, glyph
+        )
+    }
+
+    #[test]
+    fn parses_the_built_in_tiles() {
+        let tiles = TileRegistry::default();
+        assert_eq!(tiles.get(Blocks::STONE).name, "stone");
+        assert_eq!(tiles.get(Blocks::GRASS).walk_speed, 1.0);
+    }
+
+    #[test]
+    fn rejects_empty_atlas_rects() {
+        let good = tile(0, "grass", '.', 32.0, 32.0);
+        assert!(TileRegistry::parse(&format!("[{}]", good)).is_ok());
+        for (width, height) in [(0.0, 32.0), (32.0, 0.0), (0.5, 32.0), (-32.0, 32.0)] {
+            let bad = tile(1, "stone", '#', width, height);
+            assert!(matches!(
+                TileRegistry::parse(&format!("[{}, {}]", good, bad)),
+                Err(RegistryError::EmptyAtlas(name)) if name == "stone"
+            ));
+        }
+    }
+}
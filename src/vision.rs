@@ -0,0 +1,50 @@
+use crate::my_ray;
+use crate::world::World;
+use raylib::prelude::*;
+use std::collections::HashSet;
+
+// Rays cast all the way around the player, enough that neighbouring rays
+// don't skip tiles at the edge of a 16 tile view
+const VISION_RAYS: usize = 720;
+
+// What the player can see right now. Everything they've ever seen is kept by
+// the world as explored tiles, so it's saved along with the map.
+pub struct Vision {
+    visible: HashSet<(i32, i32)>,
+}
+
+impl Vision {
+    pub fn new() -> Self {
+        Vision {
+            visible: HashSet::new(),
+        }
+    }
+
+    pub fn is_visible(&self, x: i32, y: i32) -> bool {
+        self.visible.contains(&(x, y))
+    }
+
+    // Recasts what can be seen from a point. Walls are visible but hide
+    // whatever is behind them, buildings don't block the view.
+    pub fn update(&mut self, pos: Vector2, max_distance: f32, world: &mut World) {
+        let tile_size = world.tile_size as f32;
+        self.visible.clear();
+        self.visible.insert(world.tile_at(pos));
+        for i in 0..VISION_RAYS {
+            let angle = i as f32 * 360.0 / VISION_RAYS as f32;
+            my_ray::trace(pos, angle, tile_size, max_distance, world, |x, y| {
+                self.visible.insert((x, y));
+            });
+        }
+
+        for &(x, y) in &self.visible {
+            world.explore(x, y);
+        }
+    }
+}
+
+impl Default for Vision {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,182 +1,684 @@
+use crate::chunk::{self, Chunk, ChunkPos, CHUNK_SIZE};
+use crate::entity::{Blueprint, Direction, Entities, EntityId, EntityKind, PlacementError};
+use crate::light::LightMap;
+use crate::player::PlayerState;
+use crate::resource::{Resource, ResourceTile};
+use crate::save::{self, SaveError};
+use crate::terrain::{Generator, TerrainSettings};
+use crate::tiles::TileRegistry;
+use crate::vision::Vision;
 use raylib::prelude::*;
-use std::fs::File;
-use std::io::{self, Read, Write};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
 
-// TODO:
-// 1. Use sqlite for this somehow
+// How much ore one press of M takes
+const MINE_AMOUNT: u32 = 10;
 
-#[derive(Copy, Clone, Debug, PartialEq)]
-pub enum Blocks {
-    GRASS,
-    STONE,
-    PLAYER,
+// Stamps changes to tiles and buildings, so caches like the light map can tell
+// whether anything moved since they last looked. Shared by every world so a
+// stamp is never handed out twice, even when a world is replaced.
+static REVISION: AtomicU64 = AtomicU64::new(1);
+
+pub fn next_revision() -> u64 {
+    REVISION.fetch_add(1, Ordering::Relaxed)
 }
 
+// Tiles the player has seen before but can't see right now
+const EXPLORED_TINT: Color = Color::new(110, 110, 110, 255);
+
+// A tile id, what each id means comes from the TileRegistry (see tiles.ron).
+// The constants are the tiles the game itself relies on.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Blocks(pub u8);
+
 impl Blocks {
-    fn from_u8(value: u8) -> Option<Self> {
-        match value {
-            0 => Some(Blocks::GRASS),
-            1 => Some(Blocks::STONE),
-            2 => Some(Blocks::PLAYER),
-            _ => None,
-        }
+    pub const GRASS: Blocks = Blocks(0);
+    pub const STONE: Blocks = Blocks(1);
+    pub const WATER: Blocks = Blocks(3);
+    pub const SAND: Blocks = Blocks(4);
+
+    pub fn to_u8(self) -> u8 {
+        self.0
     }
-    fn to_u8(self) -> u8 {
-        self as u8
+}
+
+// Which part of the map stays put when it's resized
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Anchor {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+impl Anchor {
+    // How far existing tiles move when the map goes from `old` to `new` size
+    fn offset(self, old: (usize, usize), new: (usize, usize)) -> (i32, i32) {
+        let (fx, fy) = match self {
+            Anchor::TopLeft => (0, 0),
+            Anchor::Top => (1, 0),
+            Anchor::TopRight => (2, 0),
+            Anchor::Left => (0, 1),
+            Anchor::Center => (1, 1),
+            Anchor::Right => (2, 1),
+            Anchor::BottomLeft => (0, 2),
+            Anchor::Bottom => (1, 2),
+            Anchor::BottomRight => (2, 2),
+        };
+        let dx = new.0 as i32 - old.0 as i32;
+        let dy = new.1 as i32 - old.1 as i32;
+        (dx * fx / 2, dy * fy / 2)
     }
 }
 
 pub struct World {
-    pub data: Vec<Vec<Blocks>>,
+    chunks: HashMap<ChunkPos, Chunk>,
+    generator: Option<Generator>,
+    // Bounded worlds cover exactly width x height tiles starting at (0, 0),
+    // unbounded ones grow as they're explored
+    size: Option<(usize, usize)>,
+    // Chunks created or changed since the last save, see storage.rs
+    dirty: HashSet<ChunkPos>,
+    // Revision of the last change to each chunk's tiles, see next_revision
+    changed: HashMap<ChunkPos, u64>,
+    pub entities: Entities,
+    pub tiles: TileRegistry,
     pub tile_size: usize,
-    pub size: usize,
 }
 
 impl World {
-    pub fn new(world_size: usize) -> Self {
+    // A grass world of width x height tiles starting at tile (0, 0)
+    pub fn new(width: usize, height: usize) -> Self {
+        let mut world = Self::empty(64);
+        world.size = Some((width, height));
+        for cx in 0..width.div_ceil(CHUNK_SIZE) as i32 {
+            for cy in 0..height.div_ceil(CHUNK_SIZE) as i32 {
+                world.load_chunk(ChunkPos::new(cx, cy));
+            }
+        }
+        world
+    }
+
+    // A procedurally generated world, the same seed always gives the same map.
+    // Chunks around the origin are generated up front, the rest as they're visited.
+    pub fn generate(seed: u64, settings: TerrainSettings) -> Self {
+        let mut world = Self::empty(64);
+        world.generator = Some(Generator::new(seed, settings));
+        world.load_chunks_around(0, 0, 1);
+        world
+    }
+
+    // An unbounded world without any chunks
+    pub fn empty(tile_size: usize) -> Self {
         World {
-            data: vec![vec![Blocks::GRASS; world_size]; world_size],
-            size: world_size,
-            tile_size: 64,
+            chunks: HashMap::new(),
+            generator: None,
+            size: None,
+            dirty: HashSet::new(),
+            changed: HashMap::new(),
+            entities: Entities::new(),
+            tiles: TileRegistry::default(),
+            tile_size,
         }
     }
 
-    fn entity_to_screen(entity_pos: Vector2, camera: &Camera2D) -> Vector2 {
-        Vector2::new(
-            (entity_pos.x - camera.target.x) * camera.zoom + camera.offset.x,
-            (entity_pos.y - camera.target.y) * camera.zoom + camera.offset.y,
-        )
+    // What fills in chunks the first time they're visited, None for plain grass
+    pub fn generator(&self) -> Option<Generator> {
+        self.generator
     }
 
-    pub fn render(&self, d: &mut RaylibDrawHandle, texture_atlas: &Texture2D, camera: &Camera2D) {
-        let _ = d.begin_mode2D(*camera);
+    pub fn set_generator(&mut self, generator: Option<Generator>) {
+        self.generator = generator;
+    }
 
-        for i in 0..self.data.len() {
-            for j in 0..self.data[0].len() {
-                let tile_world_pos = Vector2::new(
-                    i as f32 * self.tile_size as f32,
-                    j as f32 * self.tile_size as f32,
-                );
-
-                // Convert the world position to screen position
-                let tile_screen_pos = Self::entity_to_screen(tile_world_pos, camera);
-
-                let dest_rect = Rectangle {
-                    x: tile_screen_pos.x,
-                    y: tile_screen_pos.y,
-                    width: self.tile_size as f32 * camera.zoom,
-                    height: self.tile_size as f32 * camera.zoom,
-                };
-
-                let texture_section = match self.data[i][j] {
-                    Blocks::STONE => Rectangle {
-                        x: 0.0,
-                        y: 32.0,
-                        width: 32.0,
-                        height: 32.0,
-                    },
-                    Blocks::GRASS => Rectangle {
-                        x: 32.0,
-                        y: 32.0,
-                        width: 32.0,
-                        height: 32.0,
-                    },
-                    _ => Rectangle {
-                        x: 0.0,
-                        y: 0.0,
-                        width: 32.0,
-                        height: 32.0,
-                    },
-                };
-
-                d.draw_texture_pro(
-                    texture_atlas,
-                    texture_section,
-                    dest_rect,
-                    Vector2::new(0.0, 0.0),
-                    0.0,
-                    Color::WHITE,
-                );
-            }
+    // Width and height in tiles, None if the world is unbounded
+    pub fn size(&self) -> Option<(usize, usize)> {
+        self.size
+    }
+
+    pub fn in_bounds(&self, x: i32, y: i32) -> bool {
+        match self.size {
+            Some((width, height)) => x >= 0 && y >= 0 && x < width as i32 && y < height as i32,
+            None => true,
         }
     }
 
-    pub fn from_file(file_name: &str) -> io::Result<Self> {
-        let mut file = File::open(file_name)?;
+    // Returns None outside the world or if the chunk holding the tile hasn't been created yet
+    pub fn get_tile(&self, x: i32, y: i32) -> Option<Blocks> {
+        if !self.in_bounds(x, y) {
+            return None;
+        }
+        let (lx, ly) = chunk::local_pos(x, y);
+        self.chunks
+            .get(&ChunkPos::from_tile(x, y))
+            .map(|chunk| chunk.get(lx, ly))
+    }
 
-        // Read the world size (4 bytes)
-        let mut size_bytes = [0u8; 4];
-        file.read_exact(&mut size_bytes)?;
-        let size = u32::from_le_bytes(size_bytes) as usize;
+    // Tiles outside a bounded world are ignored
+    pub fn set_tile(&mut self, x: i32, y: i32, block: Blocks) {
+        if !self.in_bounds(x, y) {
+            return;
+        }
+        let (lx, ly) = chunk::local_pos(x, y);
+        let pos = ChunkPos::from_tile(x, y);
+        self.load_chunk(pos).set(lx, ly, block);
+        self.dirty.insert(pos);
+        self.changed.insert(pos, next_revision());
+    }
 
-        // Read the tile size (4 bytes)
-        let mut tile_size_bytes = [0u8; 4];
-        file.read_exact(&mut tile_size_bytes)?;
-        let tile_size = f32::from_le_bytes(tile_size_bytes) as usize;
+    // Whether any tile in the rectangle between two corners changed, or came
+    // into existence, after the given revision
+    pub fn changed_since(&self, min: (i32, i32), max: (i32, i32), revision: u64) -> bool {
+        let (from, to) = (
+            ChunkPos::from_tile(min.0, min.1),
+            ChunkPos::from_tile(max.0, max.1),
+        );
+        (from.x..=to.x).any(|x| {
+            (from.y..=to.y).any(|y| {
+                self.changed
+                    .get(&ChunkPos::new(x, y))
+                    .is_some_and(|&changed| changed > revision)
+            })
+        })
+    }
 
-        // Calculate the expected number of data bytes
-        let expected_data_length = size * size;
+    pub fn get_resource(&self, x: i32, y: i32) -> Option<ResourceTile> {
+        if !self.in_bounds(x, y) {
+            return None;
+        }
+        let (lx, ly) = chunk::local_pos(x, y);
+        self.chunks
+            .get(&ChunkPos::from_tile(x, y))
+            .and_then(|chunk| chunk.get_resource(lx, ly))
+    }
+
+    pub fn set_resource(&mut self, x: i32, y: i32, resource: Option<ResourceTile>) {
+        if !self.in_bounds(x, y) {
+            return;
+        }
+        let (lx, ly) = chunk::local_pos(x, y);
+        let pos = ChunkPos::from_tile(x, y);
+        self.load_chunk(pos).set_resource(lx, ly, resource);
+        self.dirty.insert(pos);
+    }
+
+    // Takes up to `amount` ore from the tile and returns what was actually mined.
+    // The resource is removed once the tile is exhausted.
+    pub fn mine(&mut self, x: i32, y: i32, amount: u32) -> Option<(Resource, u32)> {
+        if !self.in_bounds(x, y) {
+            return None;
+        }
+        let (lx, ly) = chunk::local_pos(x, y);
+        let pos = ChunkPos::from_tile(x, y);
+        let chunk = self.chunks.get_mut(&pos)?;
+        let mut resource = chunk.get_resource(lx, ly)?;
+        self.dirty.insert(pos);
+
+        let mined = amount.min(resource.amount);
+        resource.amount -= mined;
+        if resource.amount == 0 {
+            chunk.set_resource(lx, ly, None);
+        } else {
+            chunk.set_resource(lx, ly, Some(resource));
+        }
+
+        Some((resource.kind, mined))
+    }
 
-        // Read the remaining file data
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
+    // Whether the player has ever seen the tile
+    pub fn is_explored(&self, x: i32, y: i32) -> bool {
+        if !self.in_bounds(x, y) {
+            return false;
+        }
+        let (lx, ly) = chunk::local_pos(x, y);
+        self.chunks
+            .get(&ChunkPos::from_tile(x, y))
+            .is_some_and(|chunk| chunk.is_explored(lx, ly))
+    }
 
-        // Validate the file size
-        if buffer.len() != expected_data_length {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "File size does not match expected world dimensions",
-            ));
+    // Marks a loaded tile as seen, returns whether it hadn't been before
+    pub fn explore(&mut self, x: i32, y: i32) -> bool {
+        if !self.in_bounds(x, y) {
+            return false;
+        }
+        let (lx, ly) = chunk::local_pos(x, y);
+        let pos = ChunkPos::from_tile(x, y);
+        match self.chunks.get_mut(&pos) {
+            Some(chunk) if !chunk.is_explored(lx, ly) => {
+                chunk.set_explored(lx, ly, true);
+                self.dirty.insert(pos);
+                true
+            }
+            _ => false,
         }
+    }
 
-        // Initialize the world data dynamically
-        let mut data = vec![vec![Blocks::GRASS; size]; size];
-        let mut index = 0;
+    // Unloaded tiles count as solid so nothing wanders off the edge of the world
+    pub fn is_solid(&self, x: i32, y: i32) -> bool {
+        self.get_tile(x, y)
+            .is_none_or(|block| self.tiles.is_solid(block))
+    }
 
-        for i in 0..size {
-            for j in 0..size {
-                if let Some(block) = Blocks::from_u8(buffer[index]) {
-                    data[i][j] = block;
-                } else {
-                    println!("Invalid block type in file at position {}", index);
+    // Places a building if every tile under it is open ground and free
+    pub fn place_entity(
+        &mut self,
+        kind: EntityKind,
+        x: i32,
+        y: i32,
+        direction: Direction,
+    ) -> Result<EntityId, PlacementError> {
+        for i in x..x + kind.size() {
+            for j in y..y + kind.size() {
+                if self.is_solid(i, j) {
+                    return Err(PlacementError::Blocked { x: i, y: j });
                 }
-                index += 1;
             }
         }
+        self.entities.place(kind, x, y, direction)
+    }
 
-        println!(
-            "World data (size: {}, tile_size: {}) loaded from {}",
-            size, tile_size, file_name
-        );
+    // The M, P and X actions, shared by the player and the editor's selector.
+    // Each says what happened and returns whether the world changed.
 
-        Ok(World {
-            data,
-            size,
-            tile_size,
+    pub fn mine_ore(&mut self, x: i32, y: i32) -> bool {
+        match self.mine(x, y, MINE_AMOUNT) {
+            Some((kind, amount)) => {
+                println!("Mined {} {:?}", amount, kind);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn build(&mut self, blueprint: Blueprint, x: i32, y: i32) -> bool {
+        match self.place_entity(blueprint.kind, x, y, blueprint.direction) {
+            Ok(_) => true,
+            Err(e) => {
+                println!("Can't place {:?}: {}", blueprint.kind, e);
+                false
+            }
+        }
+    }
+
+    // Removes the building covering the tile, if there is one
+    pub fn demolish(&mut self, x: i32, y: i32) -> bool {
+        match self.entities.at(x, y).map(|entity| entity.id) {
+            Some(id) => self.entities.remove(id).is_some(),
+            None => false,
+        }
+    }
+
+    pub fn load_chunk(&mut self, pos: ChunkPos) -> &mut Chunk {
+        let generator = self.generator;
+        let dirty = &mut self.dirty;
+        let changed = &mut self.changed;
+        self.chunks.entry(pos).or_insert_with(|| {
+            dirty.insert(pos);
+            changed.insert(pos, next_revision());
+            match generator {
+                Some(generator) => generator.chunk(pos),
+                None => Chunk::new(Blocks::GRASS),
+            }
         })
     }
-    pub fn data_to_file(&self, file_name: &str) -> io::Result<()> {
-        let mut file = File::create(file_name)?;
 
-        // Write the size of the world as a 4-byte integer
-        let size_bytes = (self.size as u32).to_le_bytes();
-        file.write_all(&size_bytes)?;
+    pub fn chunk(&self, pos: ChunkPos) -> Option<&Chunk> {
+        self.chunks.get(&pos)
+    }
+
+    // Puts back a chunk read from storage, it doesn't count as changed
+    pub fn insert_chunk(&mut self, pos: ChunkPos, chunk: Chunk) {
+        self.chunks.insert(pos, chunk);
+        self.dirty.remove(&pos);
+        self.changed.insert(pos, next_revision());
+    }
+
+    // Chunks that were created or changed since clear_dirty was last called
+    pub fn dirty_chunks(&self) -> impl Iterator<Item = &ChunkPos> {
+        self.dirty.iter()
+    }
+
+    pub fn clear_dirty(&mut self) {
+        self.dirty.clear();
+    }
+
+    // Makes sure every chunk within `radius` chunks of the tile exists,
+    // bounded worlds never grow past their edges
+    pub fn load_chunks_around(&mut self, tile_x: i32, tile_y: i32, radius: i32) {
+        let center = ChunkPos::from_tile(tile_x, tile_y);
+        for cx in center.x - radius..=center.x + radius {
+            for cy in center.y - radius..=center.y + radius {
+                let (x, y) = ChunkPos::new(cx, cy).origin();
+                let size = CHUNK_SIZE as i32 - 1;
+                if self.in_bounds(x, y) || self.in_bounds(x + size, y + size) {
+                    self.load_chunk(ChunkPos::new(cx, cy));
+                }
+            }
+        }
+    }
+
+    pub fn chunks(&self) -> impl Iterator<Item = (&ChunkPos, &Chunk)> {
+        self.chunks.iter()
+    }
+
+    // Tile containing a point in world space
+    pub fn tile_at(&self, pos: Vector2) -> (i32, i32) {
+        (
+            (pos.x / self.tile_size as f32).floor() as i32,
+            (pos.y / self.tile_size as f32).floor() as i32,
+        )
+    }
+
+    // The area a bounded world covers, or the smallest rectangle of tiles covering
+    // every loaded chunk of an unbounded one: (x, y, width, height)
+    pub fn bounds(&self) -> Option<(i32, i32, usize, usize)> {
+        if let Some((width, height)) = self.size {
+            return Some((0, 0, width, height));
+        }
+
+        let min_x = self.chunks.keys().map(|pos| pos.x).min()?;
+        let min_y = self.chunks.keys().map(|pos| pos.y).min()?;
+        let max_x = self.chunks.keys().map(|pos| pos.x).max()?;
+        let max_y = self.chunks.keys().map(|pos| pos.y).max()?;
+        let (x, y) = ChunkPos::new(min_x, min_y).origin();
+        Some((
+            x,
+            y,
+            (max_x - min_x + 1) as usize * CHUNK_SIZE,
+            (max_y - min_y + 1) as usize * CHUNK_SIZE,
+        ))
+    }
+
+    // Changes the map to width x height tiles. The anchor picks which edge or corner of
+    // the current map stays in place, new space is filled with `fill`.
+    // Returns how far existing tiles moved.
+    pub fn resize(
+        &mut self,
+        width: usize,
+        height: usize,
+        anchor: Anchor,
+        fill: Blocks,
+    ) -> (i32, i32) {
+        let (_, _, old_width, old_height) = self.bounds().unwrap_or((0, 0, 0, 0));
+        let (dx, dy) = anchor.offset((old_width, old_height), (width, height));
+        self.reshape(width, height, dx, dy, fill)
+    }
+
+    // Keeps only the given rectangle of the current map, which becomes the new (0, 0)
+    pub fn crop(&mut self, x: i32, y: i32, width: usize, height: usize) -> (i32, i32) {
+        let (origin_x, origin_y, _, _) = self.bounds().unwrap_or((0, 0, 0, 0));
+        self.reshape(width, height, origin_x - x, origin_y - y, Blocks::GRASS)
+    }
+
+    // Adds tiles on each side of the map
+    pub fn expand(
+        &mut self,
+        left: usize,
+        top: usize,
+        right: usize,
+        bottom: usize,
+        fill: Blocks,
+    ) -> (i32, i32) {
+        let (_, _, width, height) = self.bounds().unwrap_or((0, 0, 0, 0));
+        self.reshape(
+            width + left + right,
+            height + top + bottom,
+            left as i32,
+            top as i32,
+            fill,
+        )
+    }
+
+    // Rebuilds the world as a bounded width x height map, with the current map's
+    // top left corner landing on (dx, dy). Buildings that no longer fit are removed.
+    fn reshape(
+        &mut self,
+        width: usize,
+        height: usize,
+        dx: i32,
+        dy: i32,
+        fill: Blocks,
+    ) -> (i32, i32) {
+        let (origin_x, origin_y, _, _) = self.bounds().unwrap_or((0, 0, 0, 0));
+        let (shift_x, shift_y) = (dx - origin_x, dy - origin_y);
+
+        let mut reshaped = World::new(width, height);
+        reshaped.tile_size = self.tile_size;
+        reshaped.generator = self.generator;
+        reshaped.tiles = self.tiles.clone();
 
-        // Write the tile size as a 4-byte floating-point value
-        let tile_size_bytes = self.tile_size.to_le_bytes();
-        file.write_all(&tile_size_bytes)?;
+        for x in 0..width as i32 {
+            for y in 0..height as i32 {
+                let (old_x, old_y) = (x - shift_x, y - shift_y);
+                let block = self.get_tile(old_x, old_y).unwrap_or(fill);
+                reshaped.set_tile(x, y, block);
+                reshaped.set_resource(x, y, self.get_resource(old_x, old_y));
+                if self.is_explored(old_x, old_y) {
+                    reshaped.explore(x, y);
+                }
+            }
+        }
 
-        // Write the world data
-        for row in &self.data {
-            for &block in row {
-                file.write_all(&[block.to_u8()])?;
+        for entity in self.entities.iter() {
+            let mut moved = *entity;
+            moved.x += shift_x;
+            moved.y += shift_y;
+            if moved.footprint().all(|(x, y)| reshaped.in_bounds(x, y)) {
+                let _ = reshaped.entities.insert(moved);
             }
         }
 
+        *self = reshaped;
+        (shift_x, shift_y)
+    }
+
+    fn entity_to_screen(entity_pos: Vector2, camera: &Camera2D) -> Vector2 {
+        Vector2::new(
+            (entity_pos.x - camera.target.x) * camera.zoom + camera.offset.x,
+            (entity_pos.y - camera.target.y) * camera.zoom + camera.offset.y,
+        )
+    }
+
+    // With a vision, tiles out of sight are dimmed and ones never seen are
+    // blacked out. Without one the whole map is shown. Without a light map
+    // everything is drawn in full daylight.
+    pub fn render(
+        &self,
+        d: &mut RaylibDrawHandle,
+        texture_atlas: &Texture2D,
+        camera: &Camera2D,
+        vision: Option<&Vision>,
+        light: Option<&LightMap>,
+    ) {
+        let _ = d.begin_mode2D(*camera);
+
+        let screen_width = unsafe { raylib::ffi::GetScreenWidth() as f32 };
+        let screen_height = unsafe { raylib::ffi::GetScreenHeight() as f32 };
+        let tile_screen_size = self.tile_size as f32 * camera.zoom;
+        let chunk_screen_size = tile_screen_size * CHUNK_SIZE as f32;
+
+        for (pos, chunk) in &self.chunks {
+            let (origin_x, origin_y) = pos.origin();
+            let chunk_screen_pos = Self::entity_to_screen(
+                Vector2::new(
+                    origin_x as f32 * self.tile_size as f32,
+                    origin_y as f32 * self.tile_size as f32,
+                ),
+                camera,
+            );
+
+            // Skip chunks that are entirely off screen
+            if chunk_screen_pos.x + chunk_screen_size < 0.0
+                || chunk_screen_pos.y + chunk_screen_size < 0.0
+                || chunk_screen_pos.x > screen_width
+                || chunk_screen_pos.y > screen_height
+            {
+                continue;
+            }
+
+            for i in 0..CHUNK_SIZE {
+                for j in 0..CHUNK_SIZE {
+                    let (x, y) = (origin_x + i as i32, origin_y + j as i32);
+                    if !self.in_bounds(x, y) {
+                        continue;
+                    }
+
+                    let dest_rect = Rectangle {
+                        x: chunk_screen_pos.x + i as f32 * tile_screen_size,
+                        y: chunk_screen_pos.y + j as f32 * tile_screen_size,
+                        width: tile_screen_size,
+                        height: tile_screen_size,
+                    };
+
+                    let tint = match vision {
+                        Some(vision) if !vision.is_visible(x, y) => {
+                            if !chunk.is_explored(i, j) {
+                                d.draw_rectangle_rec(dest_rect, Color::BLACK);
+                                continue;
+                            }
+                            EXPLORED_TINT
+                        }
+                        _ => Color::WHITE,
+                    };
+                    let tint = match light {
+                        Some(light) => shade(tint, light.tint(x, y)),
+                        None => tint,
+                    };
+                    let prototype = self.tiles.get(chunk.get(i, j));
+
+                    // Textures turn to mush when tiles are only a few pixels wide
+                    if tile_screen_size < 4.0 {
+                        d.draw_rectangle_rec(dest_rect, shade(prototype.color(), tint));
+                        continue;
+                    }
+
+                    d.draw_texture_pro(
+                        texture_atlas,
+                        Rectangle::from(prototype.atlas),
+                        dest_rect,
+                        Vector2::new(0.0, 0.0),
+                        0.0,
+                        tint,
+                    );
+
+                    // Ore is drawn as specks on top of the terrain
+                    if let Some(resource) = chunk.get_resource(i, j) {
+                        d.draw_texture_pro(
+                            texture_atlas,
+                            resource.kind.texture_section(),
+                            dest_rect,
+                            Vector2::new(0.0, 0.0),
+                            0.0,
+                            tint,
+                        );
+                    }
+                }
+            }
+        }
+
+        // Buildings go on top of the terrain, the player only knows about the
+        // ones they've seen part of
+        for entity in self.entities.iter() {
+            let tint = match vision {
+                Some(vision) if !entity.footprint().any(|(x, y)| vision.is_visible(x, y)) => {
+                    if !entity.footprint().any(|(x, y)| self.is_explored(x, y)) {
+                        continue;
+                    }
+                    EXPLORED_TINT
+                }
+                _ => Color::WHITE,
+            };
+            let tint = match light {
+                Some(light) => shade(tint, light.tint(entity.x, entity.y)),
+                None => tint,
+            };
+            let size = entity.kind.size() as f32 * tile_screen_size;
+            let corner = Self::entity_to_screen(
+                Vector2::new(
+                    entity.x as f32 * self.tile_size as f32,
+                    entity.y as f32 * self.tile_size as f32,
+                ),
+                camera,
+            );
+
+            // Rotate around the middle of the footprint
+            let dest_rect = Rectangle {
+                x: corner.x + size / 2.0,
+                y: corner.y + size / 2.0,
+                width: size,
+                height: size,
+            };
+
+            d.draw_texture_pro(
+                texture_atlas,
+                entity.kind.texture_section(),
+                dest_rect,
+                Vector2::new(size / 2.0, size / 2.0),
+                entity.direction.degrees(),
+                tint,
+            );
+        }
+    }
+
+    // The player is None if the file didn't save them
+    pub fn from_file(file_name: &str) -> Result<(Self, Option<PlayerState>), SaveError> {
+        let start = Instant::now();
+        let bytes = fs::read(file_name)?;
+        let (world, player, format) = save::decode(&bytes)?;
+
+        if format == save::Format::Legacy {
+            println!(
+                "{} uses the old unversioned format, it will be upgraded on save",
+                file_name
+            );
+        }
+
         println!(
-            "World data (size: {}, tile_size: {}) saved to {}",
-            self.size, self.tile_size, file_name
+            "World data (bounds: {:?}, tile_size: {}) loaded from {} ({} bytes in {:.1?})",
+            world.bounds(),
+            world.tile_size,
+            file_name,
+            bytes.len(),
+            start.elapsed()
+        );
+
+        Ok((world, player))
+    }
+
+    pub fn data_to_file(
+        &self,
+        file_name: &str,
+        player: Option<PlayerState>,
+    ) -> Result<(), SaveError> {
+        let start = Instant::now();
+        let bytes = save::encode(self, player);
+        save::write_atomic(file_name, &bytes)?;
+
+        println!(
+            "World data (bounds: {:?}, tile_size: {}) saved to {} ({} bytes in {:.1?})",
+            self.bounds(),
+            self.tile_size,
+            file_name,
+            bytes.len(),
+            start.elapsed()
         );
         Ok(())
     }
 }
+
+// Multiplies a colour by a tint, the way raylib tints textures
+fn shade(color: Color, tint: Color) -> Color {
+    Color::new(
+        (color.r as u16 * tint.r as u16 / 255) as u8,
+        (color.g as u16 * tint.g as u16 / 255) as u8,
+        (color.b as u16 * tint.b as u16 / 255) as u8,
+        color.a,
+    )
+}